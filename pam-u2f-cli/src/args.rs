@@ -0,0 +1,192 @@
+// Copyright © Amanda Graven 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+/// The parsed command line
+#[derive(Debug, PartialEq, Eq)]
+pub struct Args {
+	/// The mapping file to operate on, if given
+	pub file: Option<PathBuf>,
+	/// What to do with it
+	pub command: Command,
+}
+
+/// A subcommand along with its arguments
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+	/// Print every user and how many keys they have
+	List,
+	/// Add a user with no keys
+	AddUser(String),
+	/// Remove a user along with their keys
+	RemoveUser(String),
+	/// Remove the key with the given handle, whoever it's mapped to
+	RemoveKey(String),
+	/// Report parse errors and likely mistakes
+	Validate,
+	/// Enroll a new key for a user with `pamu2fcfg`
+	Register {
+		/// The user to add the key to
+		user: String,
+		/// Whether the key requires the PIN
+		pin: bool,
+		/// Whether the key requires user presence
+		presence: bool,
+	},
+}
+
+/// Why the command line couldn't be turned into [`Args`]
+#[derive(Debug, PartialEq, Eq)]
+pub enum ArgsError {
+	/// Help was requested
+	Help,
+	/// The command line was malformed
+	Usage(String),
+}
+
+impl Args {
+	/// Parses the command line, without the program name. Options may appear before or after the
+	/// subcommand.
+	pub fn parse(args: impl IntoIterator<Item = OsString>) -> Result<Args, ArgsError> {
+		let mut args = args.into_iter();
+		let mut file = None;
+		let mut pin = None;
+		let mut presence = None;
+		let mut positional = Vec::new();
+		while let Some(arg) = args.next() {
+			let Some(text) = arg.to_str() else {
+				positional.push(utf8(arg)?);
+				continue;
+			};
+			match text {
+				"-h" | "--help" => return Err(ArgsError::Help),
+				"-f" | "--file" => {
+					let path = args.next().ok_or_else(|| usage("--file requires a path"))?;
+					file = Some(PathBuf::from(path));
+				}
+				"--pin" => pin = Some(true),
+				"--presence" => presence = Some(true),
+				"--no-presence" => presence = Some(false),
+				_ if text.starts_with("--file=") => {
+					file = Some(PathBuf::from(&text["--file=".len()..]));
+				}
+				_ if text.starts_with('-') && text != "-" => {
+					return Err(usage(format!("Unknown option {text}")));
+				}
+				_ => positional.push(text.to_owned()),
+			}
+		}
+		let mut positional = positional.into_iter();
+		let name = positional.next().ok_or_else(|| usage("No command given"))?;
+		let mut arg = |what: &str| {
+			positional
+				.next()
+				.ok_or_else(|| usage(format!("{name} requires {what}")))
+		};
+		let command = match name.as_str() {
+			"list" => Command::List,
+			"add-user" => Command::AddUser(arg("a username")?),
+			"remove-user" => Command::RemoveUser(arg("a username")?),
+			"remove-key" => Command::RemoveKey(arg("a key handle")?),
+			"validate" => Command::Validate,
+			"register" => Command::Register {
+				user: arg("a username")?,
+				pin: pin.take().unwrap_or(false),
+				presence: presence.take().unwrap_or(true),
+			},
+			_ => return Err(usage(format!("Unknown command {name}"))),
+		};
+		if pin.is_some() || presence.is_some() {
+			return Err(usage("Key options are only allowed with register"));
+		}
+		if let Some(extra) = positional.next() {
+			return Err(usage(format!("Unexpected argument {extra}")));
+		}
+		Ok(Args { file, command })
+	}
+}
+
+fn usage(message: impl Into<String>) -> ArgsError {
+	ArgsError::Usage(message.into())
+}
+
+/// Converts an argument that isn't a path, failing if it isn't valid UTF-8
+fn utf8(arg: OsString) -> Result<String, ArgsError> {
+	arg.into_string()
+		.map_err(|arg| usage(format!("Argument {arg:?} is not valid UTF-8")))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Args, ArgsError, Command};
+
+	fn parse(args: &[&str]) -> Result<Args, ArgsError> {
+		Args::parse(args.iter().map(Into::into))
+	}
+
+	#[test]
+	fn commands() {
+		let args = parse(&["--file", "/etc/u2f_mappings", "add-user", "alice"]).unwrap();
+		assert_eq!(args.file, Some("/etc/u2f_mappings".into()));
+		assert_eq!(args.command, Command::AddUser("alice".into()));
+		let args = parse(&["remove-key", "h", "--file=keys"]).unwrap();
+		assert_eq!(args.file, Some("keys".into()));
+		assert_eq!(args.command, Command::RemoveKey("h".into()));
+		assert_eq!(
+			parse(&["-f", "keys", "list"]).unwrap().command,
+			Command::List
+		);
+		assert_eq!(parse(&["list", "--help"]), Err(ArgsError::Help));
+		assert_eq!(parse(&["validate"]).unwrap().file, None);
+		let args = parse(&["-f", "keys", "register", "--pin", "alice"]).unwrap();
+		assert_eq!(
+			args.command,
+			Command::Register {
+				user: "alice".into(),
+				pin: true,
+				presence: true,
+			}
+		);
+		let args = parse(&["-f", "keys", "register", "alice", "--no-presence"]).unwrap();
+		assert!(matches!(
+			args.command,
+			Command::Register {
+				pin: false,
+				presence: false,
+				..
+			}
+		));
+	}
+
+	#[test]
+	fn usage_errors() {
+		for (args, message) in [
+			(&["-f", "keys"][..], "No command given"),
+			(&["-f", "keys", "frobnicate"], "Unknown command frobnicate"),
+			(
+				&["-f", "keys", "remove-user"],
+				"remove-user requires a username",
+			),
+			(
+				&["-f", "keys", "list", "alice"],
+				"Unexpected argument alice",
+			),
+			(
+				&["-f", "keys", "list", "--verbose"],
+				"Unknown option --verbose",
+			),
+			(&["list", "--file"], "--file requires a path"),
+			(
+				&["-f", "keys", "list", "--pin"],
+				"Key options are only allowed with register",
+			),
+		] {
+			assert_eq!(parse(args), Err(ArgsError::Usage(message.into())));
+		}
+	}
+}
@@ -0,0 +1,229 @@
+// Copyright © Amanda Graven 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Command line tool for editing mapping files generated by `pamu2fcfg(1)`
+
+mod args;
+
+use std::collections::BTreeSet;
+use std::io;
+use std::path::Path;
+use std::process::{self, ExitCode, Stdio};
+
+use pam_u2f_mapping::{
+	EmptyUsers, Key, LoadError, Mapping, MappingFile, ParseError, Reenrollment, UsernameProblem,
+};
+
+use args::{Args, ArgsError, Command};
+
+type BoxError = Box<dyn std::error::Error>;
+
+const USAGE: &str = "\
+Usage: pam-u2f-cli [--file <path>] <command>
+
+Commands:
+    list                   List users, how many keys each has and the keys
+    add-user <name>        Add a user without any keys
+    remove-user <name>     Remove a user along with their keys
+    remove-key <handle>    Remove the key with the given handle
+    validate               Check the file for errors and likely mistakes
+    register <name>        Enroll a new key for a user with pamu2fcfg
+
+Key options, for register:
+    --pin                  Require the PIN to be entered
+    --presence             Require user presence, this is the default
+    --no-presence          Don't require user presence
+
+Options:
+    -f, --file <path>      The mapping file to operate on. Defaults to the file
+                           pam-u2f uses for the current user when it isn't
+                           given an authfile, ~/.config/Yubico/u2f_keys
+    -h, --help             Print this help
+";
+
+fn main() -> ExitCode {
+	let args = match Args::parse(std::env::args_os().skip(1)) {
+		Ok(args) => args,
+		Err(ArgsError::Help) => {
+			print!("{USAGE}");
+			return ExitCode::SUCCESS;
+		}
+		Err(ArgsError::Usage(message)) => {
+			eprint!("pam-u2f-cli: {message}\n\n{USAGE}");
+			return ExitCode::from(2);
+		}
+	};
+	match run(&args) {
+		Ok(code) => code,
+		Err(err) => {
+			eprintln!("pam-u2f-cli: {err}");
+			ExitCode::FAILURE
+		}
+	}
+}
+
+fn run(args: &Args) -> Result<ExitCode, BoxError> {
+	let path = match &args.file {
+		Some(path) => path.clone(),
+		None => MappingFile::default_path(None)
+			.ok_or("No mapping file given and the home directory is unknown, use --file")?,
+	};
+	let path = path.as_path();
+	match &args.command {
+		Command::List => {
+			for mapping in MappingFile::load(path)?.mappings {
+				println!("{}\t{}", mapping.user, mapping.key_count());
+				for key in &mapping.keys {
+					println!("\t{}", key.summary());
+				}
+			}
+		}
+		Command::AddUser(user) => {
+			check_username(user)?;
+			let mut file = load_or_default(path)?;
+			if file.contains_user(user) {
+				return Err(format!("User {user} is already mapped").into());
+			}
+			file.add_user(user.as_str())?;
+			file.save(path)?;
+		}
+		Command::RemoveUser(user) => {
+			let mut file = MappingFile::load(path)?;
+			if file.remove_user(user).is_none() {
+				return Err(format!("User {user} is not mapped").into());
+			}
+			file.save(path)?;
+		}
+		Command::RemoveKey(handle) => {
+			let mut file = MappingFile::load(path)?;
+			if file.find_by_handle(handle).is_none() {
+				return Err(format!("No key has the handle {handle}").into());
+			}
+			let mut users = Vec::new();
+			file.retain_keys(EmptyUsers::Keep, |user, key| {
+				let remove = key.handle == *handle;
+				if remove {
+					users.push(user.to_owned());
+				}
+				!remove
+			});
+			file.save(path)?;
+			for user in users {
+				println!("Removed key {handle} from {user}");
+			}
+		}
+		Command::Validate => return validate(path),
+		Command::Register {
+			user,
+			pin,
+			presence,
+		} => {
+			check_username(user)?;
+			let mut file = load_or_default(path)?;
+			let keys = register(*pin, *presence)?;
+			let handles = keys
+				.iter()
+				.map(|key| key.handle.clone())
+				.collect::<Vec<_>>();
+			file.add_user(user.as_str())?.keys.extend(keys);
+			file.save(path)?;
+			for handle in handles {
+				println!("Added key {handle} for {user}");
+			}
+		}
+	}
+	Ok(ExitCode::SUCCESS)
+}
+
+/// Rejects usernames that would break the mapping file, and warns about ones that are unlikely to
+/// belong to an account, as local naming policies vary
+fn check_username(user: &str) -> Result<(), BoxError> {
+	Mapping::check_user(user).map_err(|err| format!("Username {user:?}: {err}"))?;
+	if let Some(problem) = UsernameProblem::check(user) {
+		eprintln!("pam-u2f-cli: warning: username {user:?} {problem}");
+	}
+	Ok(())
+}
+
+/// Loads the mapping file, starting from an empty one if it doesn't exist yet
+fn load_or_default(path: &Path) -> Result<MappingFile, LoadError> {
+	match MappingFile::load(path) {
+		Err(LoadError::Io(err)) if err.kind() == io::ErrorKind::NotFound => {
+			Ok(MappingFile::default())
+		}
+		result => result,
+	}
+}
+
+/// Enrolls a key by running `pamu2fcfg`, which prompts the user on the terminal, and parses the
+/// keys it prints
+fn register(pin: bool, presence: bool) -> Result<Vec<Key>, BoxError> {
+	let mut command = process::Command::new("pamu2fcfg");
+	command.arg("--nouser");
+	if pin {
+		command.arg("--pin-verification");
+	}
+	if !presence {
+		command.arg("--no-user-presence");
+	}
+	let output = command
+		.stderr(Stdio::inherit())
+		.output()
+		.map_err(|err| format!("Could not run pamu2fcfg: {err}"))?;
+	if !output.status.success() {
+		return Err(format!("pamu2fcfg failed ({})", output.status).into());
+	}
+	let output = String::from_utf8(output.stdout)?;
+	let keys = Mapping::parse_keys_only(output.trim())
+		.map_err(|err| format!("Could not parse pamu2fcfg output: {err}"))?;
+	if keys.is_empty() {
+		return Err("pamu2fcfg did not print a key".into());
+	}
+	Ok(keys)
+}
+
+/// Describes a line that failed to parse, explaining what to do if it's in the legacy format
+fn describe(err: &ParseError) -> String {
+	match MappingFile::upgrade_legacy(&err.text) {
+		Ok((_, report)) if !report.is_empty() => Reenrollment {
+			line: err.line,
+			..report.into_iter().next().unwrap()
+		}
+		.to_string(),
+		_ => err.to_string(),
+	}
+}
+
+/// Prints every problem found in the file, ordered by line. Fails if there were any.
+fn validate(path: &Path) -> Result<ExitCode, BoxError> {
+	let text = std::fs::read_to_string(path).map_err(LoadError::Io)?;
+	let (file, errors) = MappingFile::parse_collecting(&text);
+	let mut problems = errors
+		.iter()
+		.map(|err| (err.line, describe(err)))
+		.chain(
+			MappingFile::validate(&text)
+				.iter()
+				.map(|warning| (warning.line, warning.to_string())),
+		)
+		.collect::<Vec<_>>();
+	problems.sort_by_key(|(line, _)| *line);
+	for (_, problem) in &problems {
+		println!("{problem}");
+	}
+	let mut failed = !problems.is_empty();
+	// Duplicate users were reported above, so each user is only mentioned once
+	let mut seen = BTreeSet::new();
+	for user in file.users_without_keys().filter(|user| seen.insert(*user)) {
+		println!("User {user} has no keys");
+		failed = true;
+	}
+	Ok(if failed {
+		ExitCode::FAILURE
+	} else {
+		ExitCode::SUCCESS
+	})
+}
@@ -0,0 +1,141 @@
+// Copyright © Amanda Graven 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Runs the built binary against mapping files in the temporary directory
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+/// A mapping file that's removed along with its backup when dropped
+struct TempFile {
+	path: PathBuf,
+}
+
+impl TempFile {
+	/// Creates a file unique to the given test, with the given contents
+	fn new(test: &str, contents: &str) -> TempFile {
+		let name = format!("pam-u2f-cli-{}-{test}", std::process::id());
+		let path = std::env::temp_dir().join(name);
+		fs::write(&path, contents).unwrap();
+		TempFile { path }
+	}
+
+	fn contents(&self) -> String {
+		fs::read_to_string(&self.path).unwrap()
+	}
+
+	/// Runs the tool on the file with the given arguments
+	fn run(&self, args: &[&str]) -> Output {
+		Command::new(env!("CARGO_BIN_EXE_pam-u2f-cli"))
+			.arg("--file")
+			.arg(&self.path)
+			.args(args)
+			.output()
+			.unwrap()
+	}
+}
+
+impl Drop for TempFile {
+	fn drop(&mut self) {
+		let _ = fs::remove_file(&self.path);
+		let _ = fs::remove_file(self.path.with_extension("bak"));
+	}
+}
+
+fn stdout(output: &Output) -> &str {
+	std::str::from_utf8(&output.stdout).unwrap()
+}
+
+fn stderr(output: &Output) -> &str {
+	std::str::from_utf8(&output.stderr).unwrap()
+}
+
+#[test]
+fn list() {
+	let file = TempFile::new(
+		"list",
+		"alice:aGFuZGxlMTIz,p,es256,+presence:h,p,eddsa,\nbob\n",
+	);
+	let output = file.run(&["list"]);
+	assert!(output.status.success(), "{}", stderr(&output));
+	assert_eq!(
+		stdout(&output),
+		"alice\t2\n\tes256 aGFuZGxl… +presence\n\teddsa h\nbob\t0\n"
+	);
+}
+
+#[test]
+fn add_user() {
+	let file = TempFile::new("add_user", "alice:h,p,es256,\n");
+	assert!(file.run(&["add-user", "bob"]).status.success());
+	assert_eq!(file.contents(), "alice:h,p,es256,\nbob\n");
+	let output = file.run(&["add-user", "alice"]);
+	assert_eq!(output.status.code(), Some(1));
+	assert!(stderr(&output).contains("User alice is already mapped"));
+	let output = file.run(&["add-user", "carol:h,p,es256,"]);
+	assert_eq!(output.status.code(), Some(1));
+	assert_eq!(file.contents(), "alice:h,p,es256,\nbob\n");
+	let long = "a-very-long-username-for-a-service-account";
+	let output = file.run(&["add-user", long]);
+	assert!(output.status.success(), "{}", stderr(&output));
+	assert!(stderr(&output).contains("warning"));
+	assert_eq!(file.contents(), format!("alice:h,p,es256,\nbob\n{long}\n"));
+}
+
+#[test]
+fn remove_user() {
+	let file = TempFile::new("remove_user", "alice:h,p,es256,\nbob\n");
+	assert!(file.run(&["remove-user", "alice"]).status.success());
+	assert_eq!(file.contents(), "bob\n");
+	let output = file.run(&["remove-user", "alice"]);
+	assert_eq!(output.status.code(), Some(1));
+	assert!(stderr(&output).contains("User alice is not mapped"));
+}
+
+#[test]
+fn remove_key() {
+	let file = TempFile::new(
+		"remove_key",
+		"alice:h,p,es256,:k,p,es256,\nbob:h,p,es256,\n",
+	);
+	let output = file.run(&["remove-key", "h"]);
+	assert!(output.status.success(), "{}", stderr(&output));
+	assert_eq!(
+		stdout(&output),
+		"Removed key h from alice\nRemoved key h from bob\n"
+	);
+	assert_eq!(file.contents(), "alice:k,p,es256,\nbob\n");
+	let output = file.run(&["remove-key", "h"]);
+	assert_eq!(output.status.code(), Some(1));
+	assert!(stdout(&output).is_empty());
+}
+
+#[test]
+fn validate() {
+	let file = TempFile::new("validate_ok", "alice:h,p,es256,+presence\n");
+	let output = file.run(&["validate"]);
+	assert!(output.status.success(), "{}", stdout(&output));
+	assert!(stdout(&output).is_empty());
+	let file = TempFile::new(
+		"validate_bad",
+		"alice:h,p,es256,\nbob:h\nalice:k,p,es256,\ncarol\n",
+	);
+	let output = file.run(&["validate"]);
+	assert_eq!(output.status.code(), Some(1));
+	let lines = stdout(&output).lines().collect::<Vec<_>>();
+	assert_eq!(lines.len(), 3, "{lines:?}");
+	assert!(lines[0].starts_with("line 2: "));
+	assert!(lines[1].starts_with("line 3: User alice is already mapped"));
+	assert_eq!(lines[2], "User carol has no keys");
+}
+
+#[test]
+fn usage() {
+	let file = TempFile::new("usage", "");
+	assert_eq!(file.run(&["frobnicate"]).status.code(), Some(2));
+	assert_eq!(file.run(&["list", "--pin"]).status.code(), Some(2));
+}
@@ -0,0 +1,42 @@
+// Copyright © Amanda Graven 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Feeds arbitrary bytes to every parser. None of them may panic, and anything that parses must
+//! parse to the same thing again after being formatted.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pam_u2f_mapping::{Algorithm, Flags, Key, Mapping, MappingFile, MappingRef};
+
+fuzz_target!(|data: &[u8]| {
+	for algorithm in [Algorithm::Es256, Algorithm::Rs256, Algorithm::Eddsa] {
+		let key = Key::from_credential(b"", data, algorithm, Flags::default());
+		let _ = key.validate_public_key();
+	}
+	let Ok(s) = std::str::from_utf8(data) else {
+		let _ = MappingFile::try_from(data);
+		return;
+	};
+	if let Ok(file) = MappingFile::try_from(data) {
+		assert_eq!(file.to_string().parse::<MappingFile>().as_ref(), Ok(&file));
+	}
+	let _ = MappingFile::parse_strict(s);
+	let _ = MappingFile::parse_borrowed(s);
+	let _ = MappingFile::validate(s);
+	let _ = Mapping::parse_keys_only(s);
+	let (file, _) = MappingFile::parse_collecting(s);
+	for (_, key) in file.keys() {
+		let _ = key.validate_public_key();
+	}
+	if let Ok(file) = MappingFile::from_json(s) {
+		assert_eq!(MappingFile::from_json(&file.to_json()).as_ref(), Ok(&file));
+	}
+	for line in s.lines() {
+		let _ = line.parse::<Mapping>();
+		let _ = MappingRef::parse_borrowed(line);
+	}
+});
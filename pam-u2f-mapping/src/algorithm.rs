@@ -0,0 +1,80 @@
+// Copyright © Amanda Graven 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use alloc::borrow::ToOwned;
+use alloc::string::String;
+use core::convert::Infallible;
+use core::str::FromStr;
+
+/// The COSE algorithm of a key, as written by `pamu2fcfg`.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Algorithm {
+	/// ECDSA using P-256 and SHA-256 (`es256`)
+	Es256,
+	/// RSASSA-PKCS1-v1_5 using SHA-256 (`rs256`)
+	Rs256,
+	/// EdDSA using Ed25519 (`eddsa`)
+	Eddsa,
+	/// An algorithm not known to this crate, kept verbatim
+	Other(String),
+}
+
+impl Algorithm {
+	/// The algorithm as it appears in the mapping file
+	pub fn as_str(&self) -> &str {
+		match self {
+			Algorithm::Es256 => "es256",
+			Algorithm::Rs256 => "rs256",
+			Algorithm::Eddsa => "eddsa",
+			Algorithm::Other(s) => s,
+		}
+	}
+
+	/// A human readable name for the algorithm, suitable for display
+	pub fn label(&self) -> &str {
+		match self {
+			Algorithm::Es256 => "ES256 (ECDSA P-256)",
+			Algorithm::Rs256 => "RS256 (RSA)",
+			Algorithm::Eddsa => "EdDSA (Ed25519)",
+			Algorithm::Other(s) => s,
+		}
+	}
+}
+
+/// Parsing never fails, unknown algorithms become [`Algorithm::Other`].
+impl FromStr for Algorithm {
+	type Err = Infallible;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		Ok(match s {
+			"es256" => Algorithm::Es256,
+			"rs256" => Algorithm::Rs256,
+			"eddsa" => Algorithm::Eddsa,
+			other => Algorithm::Other(other.to_owned()),
+		})
+	}
+}
+
+impl core::fmt::Display for Algorithm {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		f.write_str(self.as_str())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::Algorithm;
+
+	#[test]
+	fn round_trip() {
+		for s in ["es256", "rs256", "eddsa", "ES256", "ecdh"] {
+			let algorithm = s.parse::<Algorithm>().unwrap();
+			assert_eq!(algorithm.as_str(), s);
+		}
+		assert!(matches!("eddsa".parse(), Ok(Algorithm::Eddsa)));
+		assert!(matches!("ES256".parse(), Ok(Algorithm::Other(_))));
+	}
+}
@@ -0,0 +1,107 @@
+// Copyright © Amanda Graven 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Minimal base64 with mandatory padding. `pamu2fcfg` writes the standard alphabet (RFC 4648 §4),
+//! which is what encoding always uses, but decoding accepts the URL-safe alphabet (RFC 4648 §5)
+//! as well, since some tools re-encode key data with it.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// The standard alphabet, indexed by 6-bit value
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Maps a base64 character to its 6-bit value. `url_safe` selects which characters encode 62
+/// and 63.
+fn value(c: u8, url_safe: bool) -> Option<u8> {
+	Some(match (c, url_safe) {
+		(b'A'..=b'Z', _) => c - b'A',
+		(b'a'..=b'z', _) => c - b'a' + 26,
+		(b'0'..=b'9', _) => c - b'0' + 52,
+		(b'+', false) | (b'-', true) => 62,
+		(b'/', false) | (b'_', true) => 63,
+		_ => return None,
+	})
+}
+
+/// Encodes bytes as padded standard base64, like `pamu2fcfg` does
+pub(crate) fn encode(bytes: &[u8]) -> String {
+	let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+	for chunk in bytes.chunks(3) {
+		let mut n = [0u8; 4];
+		n[1..=chunk.len()].copy_from_slice(chunk);
+		let n = u32::from_be_bytes(n);
+		for i in 0..4 {
+			if i <= chunk.len() {
+				out.push(char::from(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize]));
+			} else {
+				out.push('=');
+			}
+		}
+	}
+	out
+}
+
+/// Decodes padded base64, returning `None` if the input is malformed. The alphabet is detected
+/// from the input, mixing characters specific to both alphabets is an error.
+pub(crate) fn decode(s: &str) -> Option<Vec<u8>> {
+	let s = s.as_bytes();
+	if s.len() % 4 != 0 {
+		return None;
+	}
+	let url_safe = s.iter().any(|c| matches!(c, b'-' | b'_'));
+	let mut out = Vec::with_capacity(s.len() / 4 * 3);
+	let chunks = s.chunks(4);
+	let last = chunks.len().wrapping_sub(1);
+	for (i, chunk) in chunks.enumerate() {
+		let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+		if padding > 2 || (padding > 0 && i != last) {
+			return None;
+		}
+		let mut n = 0u32;
+		for &c in &chunk[..4 - padding] {
+			n = n << 6 | u32::from(value(c, url_safe)?);
+		}
+		n <<= 6 * padding as u32;
+		let bytes = n.to_be_bytes();
+		out.extend_from_slice(&bytes[1..4 - padding]);
+	}
+	Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{decode, encode};
+
+	#[test]
+	fn decode_padding() {
+		assert_eq!(decode(""), Some(vec![]));
+		assert_eq!(decode("Zg=="), Some(b"f".to_vec()));
+		assert_eq!(decode("Zm8="), Some(b"fo".to_vec()));
+		assert_eq!(decode("Zm9v"), Some(b"foo".to_vec()));
+		assert_eq!(decode("Zm9vYg=="), Some(b"foob".to_vec()));
+		assert_eq!(decode("+/+/"), Some(vec![0xfb, 0xff, 0xbf]));
+		assert_eq!(decode("Zm9"), None);
+		assert_eq!(decode("Zg==Zg=="), None);
+		assert_eq!(decode("Z==="), None);
+		assert_eq!(decode("Zm-v"), Some(vec![0x66, 0x6f, 0xaf]));
+		assert_eq!(decode("-_-_"), Some(vec![0xfb, 0xff, 0xbf]));
+		assert_eq!(decode("+_+_"), None);
+		assert_eq!(decode("Zm.v"), None);
+	}
+
+	#[test]
+	fn encode_padding() {
+		assert_eq!(encode(b""), "");
+		assert_eq!(encode(b"f"), "Zg==");
+		assert_eq!(encode(b"fo"), "Zm8=");
+		assert_eq!(encode(b"foo"), "Zm9v");
+		assert_eq!(encode(b"foob"), "Zm9vYg==");
+		assert_eq!(encode(&[0xfb, 0xff, 0xbf]), "+/+/");
+		let bytes = (0..=255).collect::<Vec<u8>>();
+		assert_eq!(decode(&encode(&bytes)), Some(bytes));
+	}
+}
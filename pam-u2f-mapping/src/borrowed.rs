@@ -0,0 +1,208 @@
+// Copyright © Amanda Graven 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use alloc::borrow::ToOwned;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use crate::{base64, entry_lines, Error, Field, Flags, Key, Mapping, MappingFile, ParseError};
+
+/// A [`Mapping`] borrowing its fields from the text it was parsed from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MappingRef<'a> {
+	/// The username the mapping applies to
+	pub user: &'a str,
+	/// The list of keys associated with the user
+	pub keys: Vec<KeyRef<'a>>,
+}
+
+/// A [`Key`] borrowing its fields from the text it was parsed from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyRef<'a> {
+	/// The key handle
+	pub handle: &'a str,
+	/// The public key data
+	pub public: &'a str,
+	/// The key algorithm
+	pub kind: &'a str,
+	/// The flags as written in the file, e.g. `+presence+pin`
+	pub flags: &'a str,
+}
+
+impl<'a> MappingRef<'a> {
+	/// Parses a single mapping line without copying any of its fields
+	pub fn parse_borrowed(s: &'a str) -> Result<MappingRef<'a>, Error> {
+		MappingRef::parse_spanned(s, false).map_err(|(error, _)| error)
+	}
+
+	/// Copies the borrowed fields into an owned [`Mapping`]
+	pub fn to_owned(&self) -> Mapping {
+		Mapping {
+			user: self.user.to_owned(),
+			keys: self.keys.iter().map(KeyRef::to_owned).collect(),
+			comments: Vec::new(),
+		}
+	}
+
+	/// Parses a single mapping line. Trailing whitespace is ignored, so a space left by an editor
+	/// doesn't end up in the last flag. On failure the byte range within the line that caused the
+	/// error is returned along with it. In strict mode, the username must not be empty, key data
+	/// is checked to be valid base64 and keys may not have more than four fields.
+	pub(crate) fn parse_spanned(
+		s: &'a str,
+		strict: bool,
+	) -> Result<MappingRef<'a>, (Error, Range<usize>)> {
+		let mut fields = s.trim_end().split(':');
+		let user = fields.next().ok_or((Error::UserMissing, 0..0))?;
+		if strict && user.is_empty() {
+			return Err((Error::EmptyUser, 0..0));
+		}
+		let mut offset = user.len() + 1;
+		let mut keys = Vec::new();
+		for (key_index, field) in fields.enumerate() {
+			let span = offset..offset + field.len();
+			offset = span.end + 1;
+			let mut subfields = field.split(',');
+			// split always yields at least one item, but there's no need to rely on it
+			let handle = subfields.next().unwrap_or_default();
+			let public = subfields
+				.next()
+				.ok_or((Error::HandleMissing { key_index }, span.clone()))?;
+			if strict {
+				let handle_span = span.start..span.start + handle.len();
+				let public_span = handle_span.end + 1..handle_span.end + 1 + public.len();
+				for (field, data, span) in [
+					(Field::Handle, handle, handle_span),
+					(Field::Public, public, public_span),
+				] {
+					if base64::decode(data).is_none() {
+						return Err((Error::InvalidBase64 { field }, span));
+					}
+				}
+			}
+			let kind = subfields
+				.next()
+				.ok_or((Error::KindMissing { key_index }, span.clone()))?;
+			let flags = subfields
+				.next()
+				.ok_or((Error::FlagsMissing { key_index }, span.clone()))?;
+			let flags_start = span.start + handle.len() + public.len() + kind.len() + 3;
+			let flags_end = flags_start + flags.len();
+			if !flags.is_empty() && !flags.starts_with('+') || has_blank_flag(flags) {
+				return Err((Error::BadFlags, flags_start..flags_end));
+			}
+			if strict && subfields.next().is_some() {
+				return Err((Error::ExtraFields, flags_end + 1..span.end));
+			}
+			keys.push(KeyRef {
+				handle,
+				public,
+				kind,
+				flags,
+			})
+		}
+		Ok(MappingRef { user, keys })
+	}
+}
+
+/// Whether any of the `+` separated flags consists only of whitespace, which PAM would never
+/// match against a known flag
+pub(crate) fn has_blank_flag(flags: &str) -> bool {
+	flags
+		.split('+')
+		.skip(1)
+		.any(|name| !name.is_empty() && name.trim().is_empty())
+}
+
+impl KeyRef<'_> {
+	/// The names of the flags set on the key
+	pub fn flag_names(&self) -> impl Iterator<Item = &str> {
+		self.flags.split('+').skip(1)
+	}
+
+	/// Copies the borrowed fields into an owned [`Key`]
+	pub fn to_owned(&self) -> Key {
+		let Ok(kind) = self.kind.parse();
+		Key {
+			handle: self.handle.to_owned(),
+			public: self.public.to_owned(),
+			kind,
+			flags: Flags::from_names(self.flag_names()),
+		}
+	}
+}
+
+impl MappingFile {
+	/// Parses every mapping in a file without copying any of their fields. Comments are skipped.
+	pub fn parse_borrowed(s: &str) -> Result<Vec<MappingRef<'_>>, ParseError> {
+		entry_lines(s)
+			.map(|(line, text)| {
+				MappingRef::parse_spanned(text, false)
+					.map_err(|err| ParseError::new(line, text, err))
+			})
+			.collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{Error, Mapping, MappingFile, MappingRef};
+	type BoxError = Box<dyn std::error::Error>;
+
+	#[test]
+	fn borrowed() -> Result<(), BoxError> {
+		let line = "alice:h,p,es256,+presence+pin:k,q,eddsa,";
+		let mapping = MappingRef::parse_borrowed(line)?;
+		assert_eq!(mapping.user, "alice");
+		assert_eq!(
+			mapping.keys[0].flag_names().collect::<Vec<_>>(),
+			["presence", "pin"]
+		);
+		assert_eq!(mapping.keys[1].flag_names().count(), 0);
+		assert_eq!(mapping.to_owned(), line.parse::<Mapping>()?);
+		let file = format!("# comment\n{line}\nbob\n");
+		let mappings = MappingFile::parse_borrowed(&file)?;
+		assert_eq!(mappings.len(), 2);
+		assert_eq!(mappings[1].user, "bob");
+		Ok(())
+	}
+
+	#[test]
+	fn trailing_whitespace() -> Result<(), BoxError> {
+		let mapping = "alice:h,p,es256,+presence \t".parse::<Mapping>()?;
+		assert_eq!(mapping.to_string(), "alice:h,p,es256,+presence");
+		assert!(mapping.keys[0].flags.other.is_empty());
+		for line in [
+			"alice:h,p,es256,+ +pin",
+			"alice:h,p,es256,+pin+\t:k,p,es256,",
+		] {
+			assert_eq!(line.parse::<Mapping>(), Err(Error::BadFlags), "{line:?}");
+		}
+		Ok(())
+	}
+
+	/// Asserts that odd input is rejected without panicking. The fuzz target in `fuzz/` covers
+	/// this more thoroughly.
+	#[test]
+	fn no_panic() {
+		for line in [
+			":",
+			"::::::::",
+			",",
+			":,",
+			":,,,",
+			"a:,,,,,,:",
+			"ålice:é,ü,ß,+ø",
+			"\u{0}:\u{feff},\r,\n,+",
+			"a:b,c,d,+e+",
+			"#:",
+		] {
+			let _ = MappingRef::parse_borrowed(line);
+			let _ = MappingFile::parse_strict(line);
+			let _ = MappingFile::validate(line);
+		}
+	}
+}
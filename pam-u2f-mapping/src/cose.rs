@@ -0,0 +1,351 @@
+// Copyright © Amanda Graven 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Checking that public keys match their algorithm. COSE keys are CBOR maps of integer labels
+//! to integers and byte strings, so the decoder below only understands definite length items and
+//! skips anything a key doesn't need.
+
+use alloc::vec::Vec;
+
+use crate::{Algorithm, Key};
+
+impl Key {
+	/// Checks that the public key is well-formed for the key's algorithm. This catches keys
+	/// whose algorithm label and key material disagree, e.g. an Ed25519 key labeled `es256`.
+	///
+	/// `pamu2fcfg` stores keys in the raw form libfido2 uses, which is accepted when it has the
+	/// length the algorithm calls for: the 64 byte x and y coordinates for `es256`, the 32 byte
+	/// key for `eddsa`, and the 256 byte modulus followed by the 3 byte exponent for `rs256`.
+	/// Otherwise the key must be a COSE key with the key type, curve and coordinates of the
+	/// algorithm, and a matching `alg` if it has one.
+	///
+	/// Only the structure is checked, not whether e.g. the point is actually on the curve.
+	pub fn validate_public_key(&self) -> Result<(), ValidationError> {
+		let raw_len = match self.kind {
+			Algorithm::Es256 => 64,
+			Algorithm::Eddsa => 32,
+			Algorithm::Rs256 => 259,
+			Algorithm::Other(_) => return Err(ValidationError::UnknownAlgorithm),
+		};
+		let data = self
+			.public_bytes()
+			.map_err(|_| ValidationError::InvalidBase64)?;
+		if data.len() == raw_len {
+			return Ok(());
+		}
+		let params = Reader { data: &data }
+			.cose_key()
+			.ok_or(ValidationError::Malformed)?;
+		let (kty, alg, crv) = match self.kind {
+			Algorithm::Es256 => (EC2, ES256, Some(P256)),
+			Algorithm::Eddsa => (OKP, EDDSA, Some(ED25519)),
+			Algorithm::Rs256 => (RSA, RS256, None),
+			Algorithm::Other(_) => unreachable!("rejected above"),
+		};
+		let int = |label| match params.iter().find(|(l, _)| *l == label) {
+			Some((_, Value::Int(value))) => Ok(Some(*value)),
+			Some(_) => Err(ValidationError::BadParameter { label }),
+			None => Ok(None),
+		};
+		let bytes = |label, len: Option<usize>| match params.iter().find(|(l, _)| *l == label) {
+			Some((_, Value::Bytes(value)))
+				if !value.is_empty() && len.map_or(true, |len| value.len() == len) =>
+			{
+				Ok(())
+			}
+			_ => Err(ValidationError::BadParameter { label }),
+		};
+		match int(KTY)? {
+			Some(found) if found == kty => {}
+			Some(found) => return Err(ValidationError::KeyTypeMismatch { kty: found }),
+			None => return Err(ValidationError::BadParameter { label: KTY }),
+		}
+		match int(ALG)? {
+			Some(found) if found != alg => {
+				return Err(ValidationError::AlgorithmMismatch { alg: found })
+			}
+			_ => {}
+		}
+		match crv {
+			Some(crv) => {
+				match int(CRV)? {
+					Some(found) if found == crv => {}
+					Some(found) => return Err(ValidationError::CurveMismatch { crv: found }),
+					None => return Err(ValidationError::BadParameter { label: CRV }),
+				}
+				bytes(X, Some(32))?;
+				if kty == EC2 {
+					bytes(Y, Some(32))?;
+				}
+			}
+			None => {
+				bytes(N, None)?;
+				bytes(E, None)?;
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Reasons a public key doesn't match its algorithm, reported by [`Key::validate_public_key`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValidationError {
+	/// The public key isn't valid base64
+	InvalidBase64,
+	/// The algorithm isn't known to this crate, so there's nothing to check the key against
+	UnknownAlgorithm,
+	/// The public key is neither the raw form of the algorithm nor a well-formed COSE key
+	Malformed,
+	/// The COSE key type (`kty`) is not the one the algorithm uses
+	KeyTypeMismatch {
+		/// The key type found
+		kty: i64,
+	},
+	/// The COSE algorithm (`alg`) is not the declared algorithm
+	AlgorithmMismatch {
+		/// The algorithm found
+		alg: i64,
+	},
+	/// The COSE curve (`crv`) is not the one the algorithm uses
+	CurveMismatch {
+		/// The curve found
+		crv: i64,
+	},
+	/// A COSE parameter the algorithm needs is missing or has the wrong type or length
+	BadParameter {
+		/// The label of the parameter, e.g. -2 for the x coordinate of an EC2 key
+		label: i64,
+	},
+}
+
+impl core::fmt::Display for ValidationError {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		match self {
+			ValidationError::InvalidBase64 => f.write_str("Public key is not valid base64"),
+			ValidationError::UnknownAlgorithm => f.write_str("Key algorithm is unknown"),
+			ValidationError::Malformed => {
+				f.write_str("Public key is neither a raw key of its algorithm nor a COSE key")
+			}
+			ValidationError::KeyTypeMismatch { kty } => {
+				write!(f, "COSE key type {kty} doesn't match the key algorithm")
+			}
+			ValidationError::AlgorithmMismatch { alg } => {
+				write!(f, "COSE algorithm {alg} doesn't match the key algorithm")
+			}
+			ValidationError::CurveMismatch { crv } => {
+				write!(f, "COSE curve {crv} doesn't match the key algorithm")
+			}
+			ValidationError::BadParameter { label } => {
+				write!(f, "COSE key parameter {label} is missing or malformed")
+			}
+		}
+	}
+}
+
+#[cfg(any(feature = "std", test))]
+impl std::error::Error for ValidationError {}
+
+// COSE key parameter labels, RFC 9052 section 7.1 and RFC 9053 section 7
+const KTY: i64 = 1;
+const ALG: i64 = 3;
+const CRV: i64 = -1;
+const X: i64 = -2;
+const Y: i64 = -3;
+const N: i64 = -1;
+const E: i64 = -2;
+
+// COSE key types, algorithms and curves, from the IANA COSE registries
+const OKP: i64 = 1;
+const EC2: i64 = 2;
+const RSA: i64 = 3;
+const ES256: i64 = -7;
+const EDDSA: i64 = -8;
+const RS256: i64 = -257;
+const P256: i64 = 1;
+const ED25519: i64 = 6;
+
+/// A decoded CBOR value. Only integers and byte strings are kept, as those are all a COSE key
+/// needs.
+enum Value<'a> {
+	Int(i64),
+	Bytes(&'a [u8]),
+	Other,
+}
+
+/// Decoder for definite length CBOR
+struct Reader<'a> {
+	data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+	/// The deepest nesting of arrays, maps and tags skipped over. A COSE key has none, the limit
+	/// only bounds the recursion in [`Reader::value`].
+	const MAX_DEPTH: usize = 16;
+
+	/// Decodes a COSE key: a map from integer labels to values, with nothing after it
+	fn cose_key(&mut self) -> Option<Vec<(i64, Value<'a>)>> {
+		let (5, len) = self.head()? else {
+			return None;
+		};
+		let mut params: Vec<(i64, Value<'a>)> = Vec::new();
+		for _ in 0..len {
+			let Value::Int(label) = self.value(1)? else {
+				return None;
+			};
+			if params.iter().any(|(l, _)| *l == label) {
+				return None;
+			}
+			params.push((label, self.value(1)?));
+		}
+		self.data.is_empty().then_some(params)
+	}
+
+	fn take(&mut self, len: u64) -> Option<&'a [u8]> {
+		let len = usize::try_from(len)
+			.ok()
+			.filter(|&len| len <= self.data.len())?;
+		let (taken, rest) = self.data.split_at(len);
+		self.data = rest;
+		Some(taken)
+	}
+
+	/// Reads the major type and argument at the start of a data item. Indefinite lengths aren't
+	/// supported.
+	fn head(&mut self) -> Option<(u8, u64)> {
+		let initial = *self.take(1)?.first()?;
+		let argument = match initial & 0x1f {
+			info @ 0..=23 => u64::from(info),
+			info @ 24..=27 => {
+				let bytes = self.take(1 << (info - 24))?;
+				bytes
+					.iter()
+					.fold(0, |value, &byte| value << 8 | u64::from(byte))
+			}
+			_ => return None,
+		};
+		Some((initial >> 5, argument))
+	}
+
+	fn value(&mut self, depth: usize) -> Option<Value<'a>> {
+		if depth > Self::MAX_DEPTH {
+			return None;
+		}
+		let (major, argument) = self.head()?;
+		Some(match major {
+			0 => i64::try_from(argument).map_or(Value::Other, Value::Int),
+			1 => i64::try_from(argument).map_or(Value::Other, |n| Value::Int(-1 - n)),
+			2 => Value::Bytes(self.take(argument)?),
+			3 => {
+				self.take(argument)?;
+				Value::Other
+			}
+			4 | 5 => {
+				let items = if major == 5 { 2 } else { 1 };
+				for _ in 0..argument.checked_mul(items)? {
+					self.value(depth + 1)?;
+				}
+				Value::Other
+			}
+			6 => {
+				self.value(depth + 1)?;
+				Value::Other
+			}
+			_ => Value::Other,
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::ValidationError;
+	use crate::{Algorithm, Flags, Key};
+
+	fn key(public: &[u8], algorithm: Algorithm) -> Key {
+		Key::from_credential(b"handle", public, algorithm, Flags::default())
+	}
+
+	/// A COSE EC2 key on P-256 with the given algorithm, curve and coordinate length
+	fn ec2(alg: u8, crv: u8, len: u8) -> Vec<u8> {
+		let mut cose = vec![0xa5, 0x01, 0x02, 0x03, alg, 0x20, crv, 0x21, 0x58, len];
+		cose.extend(vec![7; len.into()]);
+		cose.extend([0x22, 0x58, len]);
+		cose.extend(vec![9; len.into()]);
+		cose
+	}
+
+	#[test]
+	fn raw() {
+		assert_eq!(
+			key(&[1; 64], Algorithm::Es256).validate_public_key(),
+			Ok(())
+		);
+		assert_eq!(
+			key(&[1; 32], Algorithm::Eddsa).validate_public_key(),
+			Ok(())
+		);
+		assert_eq!(
+			key(&[1; 259], Algorithm::Rs256).validate_public_key(),
+			Ok(())
+		);
+		assert_eq!(
+			key(&[1; 32], Algorithm::Es256).validate_public_key(),
+			Err(ValidationError::Malformed)
+		);
+		assert_eq!(
+			key(&[1; 64], Algorithm::Other("fancy".into())).validate_public_key(),
+			Err(ValidationError::UnknownAlgorithm)
+		);
+		let mut key = key(&[1; 64], Algorithm::Es256);
+		key.public.push('!');
+		assert_eq!(
+			key.validate_public_key(),
+			Err(ValidationError::InvalidBase64)
+		);
+	}
+
+	#[test]
+	fn cose() {
+		// -7 is 0x26 and P-256 is 1
+		assert_eq!(
+			key(&ec2(0x26, 1, 32), Algorithm::Es256).validate_public_key(),
+			Ok(())
+		);
+		let cases = [
+			(
+				ec2(0x27, 1, 32),
+				ValidationError::AlgorithmMismatch { alg: -8 },
+			),
+			(ec2(0x26, 2, 32), ValidationError::CurveMismatch { crv: 2 }),
+			(
+				ec2(0x26, 1, 31),
+				ValidationError::BadParameter { label: -2 },
+			),
+			(ec2(0x26, 1, 32)[..40].to_vec(), ValidationError::Malformed),
+		];
+		for (cose, error) in cases {
+			assert_eq!(
+				key(&cose, Algorithm::Es256).validate_public_key(),
+				Err(error)
+			);
+		}
+		// An EC2 key labeled as EdDSA
+		assert_eq!(
+			key(&ec2(0x26, 1, 32), Algorithm::Eddsa).validate_public_key(),
+			Err(ValidationError::KeyTypeMismatch { kty: 2 })
+		);
+		// An OKP Ed25519 key without `alg`
+		let mut okp = vec![0xa3, 0x01, 0x01, 0x20, 0x06, 0x21, 0x58, 0x20];
+		okp.extend([5; 32]);
+		assert_eq!(key(&okp, Algorithm::Eddsa).validate_public_key(), Ok(()));
+		// Nesting deeper than the limit is rejected rather than recursed into
+		let mut nested = vec![0xa1, 0x01];
+		nested.extend([0x81; 64]);
+		assert_eq!(
+			key(&nested, Algorithm::Es256).validate_public_key(),
+			Err(ValidationError::Malformed)
+		);
+	}
+}
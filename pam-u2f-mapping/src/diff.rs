@@ -0,0 +1,147 @@
+// Copyright © Amanda Graven 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::{Key, MappingFile};
+
+/// The differences between two mapping files, created with [`MappingFile::diff`].
+///
+/// Only the first mapping for each user is compared, as that's the one PAM uses.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MappingDiff {
+	/// Users only present in the new file
+	pub added_users: Vec<String>,
+	/// Users only present in the old file
+	pub removed_users: Vec<String>,
+	/// Changes to the keys of users present in both files
+	pub changed_users: Vec<UserDiff>,
+}
+
+/// The changes to the keys of a single user. Keys are matched by their handle.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UserDiff {
+	/// The username
+	pub user: String,
+	/// Keys only present in the new file
+	pub added_keys: Vec<Key>,
+	/// Keys only present in the old file
+	pub removed_keys: Vec<Key>,
+	/// Keys present in both files but with different data or flags, as they are in the new file
+	pub changed_keys: Vec<Key>,
+}
+
+impl MappingFile {
+	/// Compares this file with a newer version of it
+	pub fn diff(&self, other: &MappingFile) -> MappingDiff {
+		let mut diff = MappingDiff::default();
+		for mapping in &self.mappings {
+			if !other.contains_user(&mapping.user) && !diff.removed_users.contains(&mapping.user) {
+				diff.removed_users.push(mapping.user.clone());
+			}
+		}
+		// Iterating in order, the first time a user is seen is the mapping PAM uses
+		for new in &other.mappings {
+			if diff.has_user(&new.user) {
+				continue;
+			}
+			let Some(old) = self.get(&new.user) else {
+				diff.added_users.push(new.user.clone());
+				continue;
+			};
+			let mut user = UserDiff {
+				user: new.user.clone(),
+				..UserDiff::default()
+			};
+			for key in &new.keys {
+				match old.find_key(&key.handle) {
+					None => user.added_keys.push(key.clone()),
+					Some(old_key) if old_key != key => user.changed_keys.push(key.clone()),
+					Some(_) => {}
+				}
+			}
+			for key in &old.keys {
+				if new.find_key(&key.handle).is_none() {
+					user.removed_keys.push(key.clone());
+				}
+			}
+			diff.changed_users.push(user);
+		}
+		diff.changed_users.retain(|user| !user.is_empty());
+		diff
+	}
+}
+
+impl MappingDiff {
+	/// Whether the files have the same users and keys
+	pub fn is_empty(&self) -> bool {
+		self.added_users.is_empty()
+			&& self.removed_users.is_empty()
+			&& self.changed_users.is_empty()
+	}
+
+	/// Whether the user has already been visited while building the diff
+	fn has_user(&self, user: &str) -> bool {
+		self.added_users.iter().any(|u| u == user)
+			|| self.changed_users.iter().any(|u| u.user == user)
+	}
+}
+
+impl UserDiff {
+	/// Whether the user's keys are unchanged
+	pub fn is_empty(&self) -> bool {
+		self.added_keys.is_empty() && self.removed_keys.is_empty() && self.changed_keys.is_empty()
+	}
+}
+
+/// Prints one change per line: `+ user` and `- user` for added and removed users, and
+/// `user: + handle`, `user: - handle` or `user: ~ handle` for added, removed and changed keys.
+impl core::fmt::Display for MappingDiff {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		for user in &self.added_users {
+			writeln!(f, "+ {user}")?;
+		}
+		for user in &self.removed_users {
+			writeln!(f, "- {user}")?;
+		}
+		for user in &self.changed_users {
+			let changes = [
+				('+', &user.added_keys),
+				('-', &user.removed_keys),
+				('~', &user.changed_keys),
+			];
+			for (sign, keys) in changes {
+				for key in keys {
+					writeln!(f, "{}: {sign} {}", user.user, key.handle)?;
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::MappingFile;
+	type BoxError = Box<dyn std::error::Error>;
+
+	#[test]
+	fn diff() -> Result<(), BoxError> {
+		let old: MappingFile =
+			"alice:a,p,es256,:b,p,es256,\nbob:c,p,es256,\ncarol:d,p,es256,".parse()?;
+		let new: MappingFile = "alice:b,p,es256,+pin:e,p,es256,\ncarol:d,p,es256,\ndave\n\
+			alice:f,p,es256,"
+			.parse()?;
+		let diff = old.diff(&new);
+		assert_eq!(
+			diff.to_string(),
+			"+ dave\n- bob\nalice: + e\nalice: - a\nalice: ~ b\n"
+		);
+		assert!(old.diff(&old).is_empty());
+		Ok(())
+	}
+}
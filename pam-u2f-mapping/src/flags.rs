@@ -0,0 +1,337 @@
+// Copyright © Amanda Graven 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use alloc::borrow::ToOwned;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::str::FromStr;
+
+use crate::Error;
+
+/// The options set on a key, written as e.g. `+presence+pin` in the mapping file.
+///
+/// Parsed flags format the way they were written, including their order and any repeated flags,
+/// so a file doesn't change just by being loaded and saved. Flags that are set after parsing, or
+/// on flags built by hand, are written after those in the order `presence`, `pin`,
+/// `verification`, followed by unknown flags. [`MappingFile::normalize`] rewrites every key's
+/// flags in that order.
+///
+/// Two sets of flags compare equal if the same flags are set, however they're written.
+///
+/// [`MappingFile::normalize`]: crate::MappingFile::normalize
+#[derive(Clone, Debug, Default)]
+pub struct Flags {
+	/// User presence is required (`presence`)
+	pub presence: bool,
+	/// The PIN must be entered (`pin`)
+	pub pin: bool,
+	/// User verification is required (`verification`)
+	pub verification: bool,
+	/// Flags not known to this crate, kept so they aren't lost when formatting
+	pub other: Vec<String>,
+	/// Every flag in the order it was parsed, used to format the flags the same way
+	written: Vec<String>,
+}
+
+/// The flags known to this crate, in the order they're written when not parsed
+const KNOWN: [&str; 3] = ["presence", "pin", "verification"];
+
+impl PartialEq for Flags {
+	fn eq(&self, other: &Flags) -> bool {
+		self.key() == other.key()
+	}
+}
+
+impl Eq for Flags {}
+
+impl PartialOrd for Flags {
+	fn partial_cmp(&self, other: &Flags) -> Option<core::cmp::Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+impl Ord for Flags {
+	fn cmp(&self, other: &Flags) -> core::cmp::Ordering {
+		self.key().cmp(&other.key())
+	}
+}
+
+impl core::hash::Hash for Flags {
+	fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
+		self.key().hash(state);
+	}
+}
+
+impl FromStr for Flags {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let mut parts = s.split('+');
+		if parts.next() != Some("") || crate::borrowed::has_blank_flag(s) {
+			return Err(Error::BadFlags);
+		}
+		Ok(Flags::from_names(parts))
+	}
+}
+
+impl Flags {
+	/// Checks that the flags format to something that parses back to the same flags. Fails with
+	/// [`Error::BadFlags`] if an unknown flag contains `+`, `,`, `:` or a line break, consists
+	/// only of whitespace, or is the name of a known flag.
+	///
+	/// Parsed flags always pass. Formatting doesn't perform this check, so flags assembled by hand
+	/// should be checked before being written.
+	pub fn check(&self) -> Result<(), Error> {
+		for flag in &self.other {
+			if flag.contains(['+', ',', ':', '\n', '\r'])
+				|| !flag.is_empty() && flag.trim().is_empty()
+				|| KNOWN.contains(&flag.as_str())
+			{
+				return Err(Error::BadFlags);
+			}
+		}
+		Ok(())
+	}
+
+	/// Builds the flags from the individual flag names, without the `+` separators
+	pub(crate) fn from_names<'a>(names: impl IntoIterator<Item = &'a str>) -> Flags {
+		let mut flags = Flags::default();
+		for name in names {
+			match name {
+				"presence" => flags.presence = true,
+				"pin" => flags.pin = true,
+				"verification" => flags.verification = true,
+				other => flags.other.push(other.to_owned()),
+			}
+			flags.written.push(name.to_owned());
+		}
+		flags
+	}
+
+	/// The names of the flags, without the `+` separators, in the order they're formatted in.
+	/// Flags that were parsed come first, in the order they were written, followed by flags set
+	/// since.
+	pub fn names(&self) -> impl Iterator<Item = &str> {
+		let set = [self.presence, self.pin, self.verification];
+		let mut written = [false; 3];
+		let mut other = 0;
+		let mut names = Vec::new();
+		for name in &self.written {
+			if let Some(index) = KNOWN.iter().position(|known| known == name) {
+				if set[index] {
+					names.push(KNOWN[index]);
+					written[index] = true;
+				}
+			} else if self.other.get(other) == Some(name) {
+				// Unknown flags removed since parsing no longer line up and are skipped
+				names.push(name.as_str());
+				other += 1;
+			}
+		}
+		for index in 0..KNOWN.len() {
+			if set[index] && !written[index] {
+				names.push(KNOWN[index]);
+			}
+		}
+		names.extend(self.other[other..].iter().map(String::as_str));
+		names.into_iter()
+	}
+
+	/// Forgets the order the flags were parsed in, so they're formatted in the canonical order
+	pub(crate) fn forget_order(&mut self) {
+		self.written.clear();
+	}
+
+	/// The parts of the flags that take part in comparisons
+	fn key(&self) -> (bool, bool, bool, &[String]) {
+		(self.presence, self.pin, self.verification, &self.other)
+	}
+}
+
+/// The known flags of a key as a bit set, for checking several flags at once without looking at
+/// each field. Unknown flags have no bit. Created with [`Flags::bits`] or
+/// [`crate::Key::flag_bits`].
+///
+/// ```
+/// use pam_u2f_mapping::{FlagBits, Flags};
+///
+/// let flags: Flags = "+presence+pin+fancy".parse().unwrap();
+/// assert!(flags.bits().contains(FlagBits::PRESENCE | FlagBits::PIN));
+/// assert!(!flags.bits().contains(FlagBits::VERIFICATION));
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FlagBits(u8);
+
+impl FlagBits {
+	/// User presence is required
+	pub const PRESENCE: FlagBits = FlagBits(1 << 0);
+	/// The PIN must be entered
+	pub const PIN: FlagBits = FlagBits(1 << 1);
+	/// User verification is required
+	pub const VERIFICATION: FlagBits = FlagBits(1 << 2);
+
+	/// No flags set
+	pub const fn empty() -> FlagBits {
+		FlagBits(0)
+	}
+
+	/// Every known flag set
+	pub const fn all() -> FlagBits {
+		FlagBits(Self::PRESENCE.0 | Self::PIN.0 | Self::VERIFICATION.0)
+	}
+
+	/// The raw bits
+	pub const fn bits(self) -> u8 {
+		self.0
+	}
+
+	/// Whether no flags are set
+	pub const fn is_empty(self) -> bool {
+		self.0 == 0
+	}
+
+	/// Whether every flag in `other` is set
+	pub const fn contains(self, other: FlagBits) -> bool {
+		self.0 & other.0 == other.0
+	}
+
+	/// Whether any flag in `other` is set
+	pub const fn intersects(self, other: FlagBits) -> bool {
+		self.0 & other.0 != 0
+	}
+
+	/// Sets the flags in `other`
+	pub fn insert(&mut self, other: FlagBits) {
+		self.0 |= other.0;
+	}
+
+	/// Clears the flags in `other`
+	pub fn remove(&mut self, other: FlagBits) {
+		self.0 &= !other.0;
+	}
+}
+
+impl core::ops::BitOr for FlagBits {
+	type Output = FlagBits;
+
+	fn bitor(self, other: FlagBits) -> FlagBits {
+		FlagBits(self.0 | other.0)
+	}
+}
+
+impl core::ops::BitOrAssign for FlagBits {
+	fn bitor_assign(&mut self, other: FlagBits) {
+		self.insert(other);
+	}
+}
+
+impl core::ops::BitAnd for FlagBits {
+	type Output = FlagBits;
+
+	fn bitand(self, other: FlagBits) -> FlagBits {
+		FlagBits(self.0 & other.0)
+	}
+}
+
+impl core::ops::BitAndAssign for FlagBits {
+	fn bitand_assign(&mut self, other: FlagBits) {
+		self.0 &= other.0;
+	}
+}
+
+impl Flags {
+	/// The known flags as a bit set
+	pub fn bits(&self) -> FlagBits {
+		let mut bits = FlagBits::empty();
+		for (set, bit) in [
+			(self.presence, FlagBits::PRESENCE),
+			(self.pin, FlagBits::PIN),
+			(self.verification, FlagBits::VERIFICATION),
+		] {
+			if set {
+				bits.insert(bit);
+			}
+		}
+		bits
+	}
+}
+
+impl core::fmt::Display for Flags {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		for flag in self.names() {
+			write!(f, "+{flag}")?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{FlagBits, Flags};
+	use crate::Error;
+	type BoxError = Box<dyn std::error::Error>;
+
+	#[test]
+	fn parse() -> Result<(), BoxError> {
+		let flags: Flags = "+presence+pin+fancy".parse()?;
+		assert!(flags.presence && flags.pin && !flags.verification);
+		assert_eq!(flags.other, &["fancy"]);
+		assert_eq!(flags.to_string(), "+presence+pin+fancy");
+		assert_eq!("".parse::<Flags>()?.to_string(), "");
+		assert_eq!("presence".parse::<Flags>().unwrap_err(), Error::BadFlags);
+		assert_eq!("+pin+ ".parse::<Flags>().unwrap_err(), Error::BadFlags);
+		Ok(())
+	}
+
+	#[test]
+	fn order() -> Result<(), BoxError> {
+		for s in [
+			"+verification+pin",
+			"+pin+pin",
+			"+x+presence+x",
+			"+pin+presence+",
+		] {
+			assert_eq!(s.parse::<Flags>()?.to_string(), s);
+		}
+		let mut flags: Flags = "+verification+x+pin+y".parse()?;
+		assert_eq!(flags, "+pin+verification+x+y".parse()?);
+		flags.verification = false;
+		flags.presence = true;
+		flags.other.remove(0);
+		flags.other.push("z".into());
+		assert_eq!(flags.to_string(), "+pin+y+presence+z");
+		flags.forget_order();
+		assert_eq!(flags.to_string(), "+presence+pin+y+z");
+		Ok(())
+	}
+
+	#[test]
+	fn check() -> Result<(), BoxError> {
+		"+presence+fancy+".parse::<Flags>()?.check()?;
+		for flag in ["a+b", "a,b", "a:b", "a\nb", " ", "pin"] {
+			let flags = Flags {
+				other: vec![flag.into()],
+				..Flags::default()
+			};
+			assert_eq!(flags.check(), Err(Error::BadFlags), "{flag:?}");
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn bits() -> Result<(), BoxError> {
+		let bits = "+verification+fancy+pin".parse::<Flags>()?.bits();
+		assert_eq!(bits, FlagBits::PIN | FlagBits::VERIFICATION);
+		assert!(bits.intersects(FlagBits::PIN | FlagBits::PRESENCE));
+		assert!(!bits.contains(FlagBits::all()));
+		assert!("+fancy".parse::<Flags>()?.bits().is_empty());
+		let mut bits = FlagBits::all();
+		bits.remove(FlagBits::PRESENCE);
+		assert_eq!(bits & FlagBits::PRESENCE, FlagBits::empty());
+		assert_eq!(bits.bits(), 0b110);
+		Ok(())
+	}
+}
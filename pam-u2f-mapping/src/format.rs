@@ -0,0 +1,204 @@
+// Copyright © Amanda Graven 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use alloc::borrow::ToOwned;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use core::mem;
+
+use crate::{entry_lines, lines, KeyRef, Line, Mapping, MappingFile, MappingRef, ParseError};
+
+/// The format of a mapping file, as detected by [`MappingFile::detect_format`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+	/// The format written by `pamu2fcfg` before version 1.1, where each key is just a key handle
+	/// and a public key, e.g. `alice:handle,public`. This crate can't parse it.
+	U2fLegacy,
+	/// The current format, where each key also has an algorithm and flags, e.g.
+	/// `alice:handle,public,es256,+presence`
+	Fido2,
+	/// The format couldn't be told, because the file has no keys, mixes both formats or has keys
+	/// in neither
+	Unknown,
+}
+
+impl Format {
+	/// Detects the format of a single key, i.e. the text between two colons
+	pub(crate) fn of_key(field: &str) -> Format {
+		let subfields = field.split(',').collect::<Vec<_>>();
+		match subfields[..] {
+			[handle, public] if !handle.is_empty() && !public.is_empty() => Format::U2fLegacy,
+			[_, _, kind, _] if !kind.is_empty() => Format::Fido2,
+			_ => Format::Unknown,
+		}
+	}
+}
+
+impl MappingFile {
+	/// Detects which format a mapping file is written in, by looking at how many fields each key
+	/// has. Files in the [`Format::U2fLegacy`] format fail to parse, so this is useful for telling
+	/// the user why.
+	///
+	/// The format is only reported if every key in the file agrees on it, otherwise the result is
+	/// [`Format::Unknown`].
+	pub fn detect_format(s: &str) -> Format {
+		let mut keys = entry_lines(s)
+			.flat_map(|(_, line)| line.split(':').skip(1))
+			.map(Format::of_key);
+		let Some(first) = keys.next() else {
+			return Format::Unknown;
+		};
+		if keys.all(|format| format == first) {
+			first
+		} else {
+			Format::Unknown
+		}
+	}
+}
+
+/// A user with keys in the [`Format::U2fLegacy`] format, which have to be enrolled again. Returned
+/// by [`MappingFile::upgrade_legacy`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reenrollment {
+	/// The line the user's mapping is on, starting at 1
+	pub line: usize,
+	/// The username
+	pub user: String,
+	/// How many of the user's keys were in the legacy format
+	pub keys: usize,
+}
+
+impl MappingFile {
+	/// Parses a mapping file that may contain keys in the [`Format::U2fLegacy`] format, leaving
+	/// those keys out. Keys in the current format are parsed as usual. Alongside the file, every
+	/// user that had legacy keys is returned, in file order.
+	///
+	/// Legacy keys lack the information the current format needs, so they can't be converted.
+	/// Instead, the user has to touch their key again to enroll it anew:
+	///
+	/// 1. Parse the old file with this method and show the returned list to the administrator.
+	/// 2. Have each listed user run `pamu2fcfg -n` and add the output to their mapping, e.g. with
+	///    [`Mapping::parse_keys_only`].
+	/// 3. Save the file. Users whose keys were all legacy are kept without any keys until then, so
+	///    they're also listed by [`MappingFile::users_without_keys`].
+	pub fn upgrade_legacy(s: &str) -> Result<(MappingFile, Vec<Reenrollment>), ParseError> {
+		let mut file = MappingFile::default();
+		let mut report = Vec::new();
+		let mut comments = Vec::new();
+		for line in lines(s) {
+			let (line, text) = match line {
+				Line::Comment(text) => {
+					comments.push(text.to_owned());
+					continue;
+				}
+				Line::Entry(line, text) => (line, text),
+			};
+			let mut fields = text.split(':');
+			let user = fields.next().unwrap_or_default();
+			let mut mapping = Mapping {
+				user: user.to_owned(),
+				keys: Vec::new(),
+				comments: mem::take(&mut comments),
+			};
+			let mut legacy = 0;
+			// The key fields start after the colon following the username
+			let mut start = user.len() + 1;
+			for (key_index, field) in fields.enumerate() {
+				let end = start + field.len();
+				if Format::of_key(field) == Format::U2fLegacy {
+					legacy += 1;
+				} else {
+					// Parse the field together with the colon before it, as a line with no user
+					let key = MappingRef::parse_spanned(&text[start - 1..end], false).map_err(
+						|(error, span)| {
+							let span = span.start + start - 1..span.end + start - 1;
+							let error = error.offset_key_index(key_index);
+							ParseError::new(line, text, (error, span))
+						},
+					)?;
+					mapping.keys.extend(key.keys.iter().map(KeyRef::to_owned));
+				}
+				start = end + 1;
+			}
+			if legacy > 0 {
+				report.push(Reenrollment {
+					line,
+					user: mapping.user.clone(),
+					keys: legacy,
+				});
+			}
+			file.mappings.push(mapping);
+		}
+		file.trailing_comments = comments;
+		Ok((file, report))
+	}
+}
+
+impl core::fmt::Display for Reenrollment {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		let plural = if self.keys == 1 { "" } else { "s" };
+		write!(
+			f,
+			"line {}: User {} has {} legacy U2F key{plural} that must be enrolled again",
+			self.line, self.user, self.keys
+		)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{Error, Format, MappingFile, Reenrollment};
+
+	#[test]
+	fn detect() {
+		for (file, format) in [
+			("alice:h,p,es256,+presence\nbob:h,p,eddsa,", Format::Fido2),
+			("# old\nalice:h,04ab:k,04cd\nbob:h,04ef", Format::U2fLegacy),
+			("alice:h,p,es256,+presence\nbob:h,04ab", Format::Unknown),
+			("alice:h,p,,\n", Format::Unknown),
+			("alice:h\n", Format::Unknown),
+			("alice\nbob\n", Format::Unknown),
+			("", Format::Unknown),
+		] {
+			assert_eq!(MappingFile::detect_format(file), format, "{file:?}");
+		}
+	}
+
+	#[test]
+	fn upgrade() {
+		let old = "alice:h,04ab:k,p,es256,+presence\n# bob\nbob:h,04cd:h,04ef\ncarol:c,p,eddsa,\n";
+		let (file, report) = MappingFile::upgrade_legacy(old).unwrap();
+		assert_eq!(
+			file.to_string(),
+			"alice:k,p,es256,+presence\n# bob\nbob\ncarol:c,p,eddsa,\n"
+		);
+		assert_eq!(
+			report,
+			[
+				Reenrollment {
+					line: 1,
+					user: "alice".into(),
+					keys: 1,
+				},
+				Reenrollment {
+					line: 3,
+					user: "bob".into(),
+					keys: 2,
+				},
+			]
+		);
+		assert_eq!(
+			report[1].to_string(),
+			"line 3: User bob has 2 legacy U2F keys that must be enrolled again"
+		);
+		let err = MappingFile::upgrade_legacy("alice:h,04ab:k,p,es256").unwrap_err();
+		assert_eq!(
+			(err.line, err.error, err.span),
+			(1, Error::FlagsMissing { key_index: 1 }, 13..22)
+		);
+	}
+}
@@ -0,0 +1,580 @@
+// Copyright © Amanda Graven 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{is_comment, is_skipped, trim_cr, Error, Mapping, MappingFile, ParseError};
+
+impl MappingFile {
+	/// Reads and parses a mapping file line by line
+	pub fn from_reader<R: BufRead>(reader: R) -> Result<MappingFile, LoadError> {
+		let mut lines = Mapping::parse_lines(reader);
+		let mappings = lines.by_ref().collect::<Result<Vec<_>, _>>()?;
+		Ok(MappingFile {
+			mappings,
+			trailing_comments: lines.comments,
+		})
+	}
+
+	/// Reads and parses the mapping file at the given path
+	pub fn load(path: impl AsRef<Path>) -> Result<MappingFile, LoadError> {
+		MappingFile::from_reader(BufReader::new(File::open(path)?))
+	}
+
+	/// Formats the mapping file and writes it to the given path, first backing up the existing
+	/// file. This is the same as [`MappingFile::save_with`] using the default [`SaveOptions`].
+	pub fn save(&self, path: impl AsRef<Path>) -> Result<(), SaveError> {
+		self.save_with(path, &SaveOptions::default())
+	}
+
+	/// Formats the mapping file and writes it to the given path.
+	///
+	/// The file is replaced atomically: the contents are written to a temporary file in the same
+	/// directory, which is then renamed over the original. If the file already exists, its
+	/// permissions and owner are carried over. If anything fails, the original file is left
+	/// untouched. A symlink at `path` is followed, replacing the file it points to.
+	///
+	/// Unless disabled in `options`, an existing file is first copied to a backup next to it, see
+	/// [`SaveOptions::backup`].
+	///
+	/// Nothing is written if a mapping wouldn't parse back the same, see [`Mapping::check`], so
+	/// a saved file can always be loaded again.
+	pub fn save_with(
+		&self,
+		path: impl AsRef<Path>,
+		options: &SaveOptions,
+	) -> Result<(), SaveError> {
+		for mapping in &self.mappings {
+			mapping.check().map_err(|error| SaveError::Invalid {
+				user: mapping.user.clone(),
+				error,
+			})?;
+		}
+		let path = path.as_ref();
+		let path = match fs::canonicalize(path) {
+			Ok(path) => path,
+			Err(err) if err.kind() == io::ErrorKind::NotFound => path.to_owned(),
+			Err(err) => return Err(err.into()),
+		};
+		let original = match fs::metadata(&path) {
+			Ok(metadata) => Some(metadata),
+			Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+			Err(err) => return Err(err.into()),
+		};
+		if let Some(original) = original.as_ref().filter(|_| options.backup) {
+			backup(&path, original)?;
+		}
+		let contents = self.to_string();
+		let temp = write_temp(&path, original.as_ref(), |file| {
+			file.write_all(contents.as_bytes())
+		})?;
+		rename_temp(&temp, &path)?;
+		// Persist the rename itself. Not every platform supports syncing a directory, and the
+		// file is already in place, so failure here isn't reported.
+		if let Some(dir) = path.parent() {
+			let _ = File::open(dir).and_then(|dir| dir.sync_all());
+		}
+		Ok(())
+	}
+
+	/// The mapping file the PAM module uses for a user when it isn't given an `authfile=`
+	/// argument. If `user` is `None`, the path is for the user running this process.
+	///
+	/// The first of these that applies is returned:
+	///
+	/// 1. `$XDG_CONFIG_HOME/Yubico/u2f_keys`, if the variable is set to an absolute path. Only
+	///    used when `user` is `None`, since the environment belongs to the current user.
+	/// 2. `~/.config/Yubico/u2f_keys`, where `~` is `$HOME` for the current user, or the home
+	///    directory listed in `/etc/passwd` for any other user.
+	///
+	/// Returns `None` if the home directory can't be found. Accounts that only exist in other
+	/// NSS databases, such as LDAP, aren't looked up. System-wide files like `/etc/u2f_mappings`
+	/// are only used when configured with `authfile=`, so they're never returned.
+	pub fn default_path(user: Option<&str>) -> Option<PathBuf> {
+		let home = match user {
+			None => {
+				let config = std::env::var_os("XDG_CONFIG_HOME").map(PathBuf::from);
+				if let Some(config) = config.filter(|config| config.is_absolute()) {
+					return Some(config.join(USER_FILE));
+				}
+				PathBuf::from(std::env::var_os("HOME").filter(|home| !home.is_empty())?)
+			}
+			Some(user) => {
+				let passwd = fs::read_to_string("/etc/passwd").ok()?;
+				PathBuf::from(passwd_home(&passwd, user)?)
+			}
+		};
+		Some(home.join(".config").join(USER_FILE))
+	}
+}
+
+/// The per-user mapping file, relative to the configuration directory
+const USER_FILE: &str = "Yubico/u2f_keys";
+
+/// Finds the home directory of a user in the contents of `/etc/passwd`
+fn passwd_home<'a>(passwd: &'a str, user: &str) -> Option<&'a str> {
+	passwd.lines().find_map(|line| {
+		let mut fields = line.split(':');
+		if fields.next() != Some(user) {
+			return None;
+		}
+		fields.nth(4).filter(|home| !home.is_empty())
+	})
+}
+
+/// Options for [`MappingFile::save_with`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SaveOptions {
+	backup: bool,
+}
+
+impl Default for SaveOptions {
+	fn default() -> Self {
+		SaveOptions { backup: true }
+	}
+}
+
+impl SaveOptions {
+	/// Creates the default options, with backups enabled
+	pub fn new() -> Self {
+		SaveOptions::default()
+	}
+
+	/// Sets whether the existing file is copied to a backup before it's replaced. Enabled by
+	/// default.
+	///
+	/// The backup is written next to the file with `.bak` appended to its name, e.g.
+	/// `u2f_mappings.bak`, replacing any earlier backup. Being in the same directory keeps it on
+	/// the same filesystem, so it's replaced with an atomic rename as well. Its permissions are
+	/// copied from the file.
+	pub fn backup(mut self, backup: bool) -> Self {
+		self.backup = backup;
+		self
+	}
+}
+
+/// The backup written by [`MappingFile::save_with`] before replacing `path`
+fn backup_path(path: &Path) -> PathBuf {
+	let name = path.file_name().unwrap_or_default().to_string_lossy();
+	path.with_file_name(format!("{name}.bak"))
+}
+
+/// Copies the file at `path` to its backup. The copy is made under a temporary name first, so a
+/// failure doesn't leave a partial backup behind in place of the previous one.
+fn backup(path: &Path, original: &fs::Metadata) -> io::Result<()> {
+	let backup = backup_path(path);
+	let temp = write_temp(&backup, Some(original), |file| {
+		io::copy(&mut File::open(path)?, file).map(drop)
+	})?;
+	rename_temp(&temp, &backup)
+}
+
+/// Creates a temporary file next to `path`, so the final rename doesn't cross filesystems, fills
+/// it using `write` and syncs it. Permissions and ownership are copied from `original` before
+/// anything is written, so the contents are never readable by more users than the original
+/// allows.
+///
+/// The name is unique to the call, and another is tried if the file exists anyway, so the file
+/// removed if something fails is always the one this call created.
+fn write_temp(
+	path: &Path,
+	original: Option<&fs::Metadata>,
+	write: impl FnOnce(&mut File) -> io::Result<()>,
+) -> io::Result<PathBuf> {
+	static COUNTER: AtomicUsize = AtomicUsize::new(0);
+	let name = path.file_name().unwrap_or_default().to_string_lossy();
+	let mut options = File::options();
+	options.write(true).create_new(true);
+	// Only accessible to the owner until the original's permissions are copied
+	#[cfg(unix)]
+	if original.is_some() {
+		use std::os::unix::fs::OpenOptionsExt;
+		options.mode(0o600);
+	}
+	let mut attempts = 0;
+	let (temp, mut file) = loop {
+		let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+		let temp = path.with_file_name(format!(".{name}.tmp{}.{count}", std::process::id()));
+		match options.open(&temp) {
+			Ok(file) => break (temp, file),
+			Err(err) if err.kind() == io::ErrorKind::AlreadyExists && attempts < 100 => {
+				attempts += 1;
+			}
+			Err(err) => return Err(err),
+		}
+	};
+	let result = fill_temp(&mut file, original, write);
+	drop(file);
+	match result {
+		Ok(()) => Ok(temp),
+		Err(err) => {
+			let _ = fs::remove_file(&temp);
+			Err(err)
+		}
+	}
+}
+
+/// Copies permissions and ownership to a newly created temporary file, then writes and syncs it
+fn fill_temp(
+	file: &mut File,
+	original: Option<&fs::Metadata>,
+	write: impl FnOnce(&mut File) -> io::Result<()>,
+) -> io::Result<()> {
+	if let Some(original) = original {
+		file.set_permissions(original.permissions())?;
+		#[cfg(unix)]
+		{
+			use std::os::unix::fs::MetadataExt;
+			let current = file.metadata()?;
+			if (current.uid(), current.gid()) != (original.uid(), original.gid()) {
+				std::os::unix::fs::fchown(&*file, Some(original.uid()), Some(original.gid()))?;
+			}
+		}
+	}
+	write(file)?;
+	file.sync_all()
+}
+
+/// Moves a temporary file from [`write_temp`] over `path`, removing it if that fails
+fn rename_temp(temp: &Path, path: &Path) -> io::Result<()> {
+	let result = fs::rename(temp, path);
+	if result.is_err() {
+		let _ = fs::remove_file(temp);
+	}
+	result
+}
+
+impl Mapping {
+	/// Parses mappings one line at a time from a reader, without reading it into memory first.
+	/// Blank lines are skipped, and comments are attached to the mapping below them like when
+	/// parsing a [`MappingFile`]. Comments after the last mapping aren't returned.
+	pub fn parse_lines<R: BufRead>(reader: R) -> ParseLines<R> {
+		ParseLines::new(reader)
+	}
+}
+
+/// Iterator over the mappings read from a [`BufRead`], returned by [`Mapping::parse_lines`]
+#[derive(Debug)]
+pub struct ParseLines<R> {
+	lines: io::Lines<R>,
+	line: usize,
+	/// Comments read since the last mapping
+	comments: Vec<String>,
+}
+
+impl<R: BufRead> ParseLines<R> {
+	pub(crate) fn new(reader: R) -> Self {
+		ParseLines {
+			lines: reader.lines(),
+			line: 0,
+			comments: Vec::new(),
+		}
+	}
+}
+
+impl<R: BufRead> Iterator for ParseLines<R> {
+	type Item = Result<Mapping, LoadError>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		loop {
+			let text = match self.lines.next()? {
+				Ok(text) => text,
+				Err(err) => return Some(Err(err.into())),
+			};
+			self.line += 1;
+			let text = trim_cr(&text);
+			if is_comment(text) {
+				self.comments.push(text.to_owned());
+			}
+			if is_skipped(text) {
+				continue;
+			}
+			let line = self.line;
+			let comments = std::mem::take(&mut self.comments);
+			return Some(
+				Mapping::parse_spanned(text, false)
+					.map(|mapping| Mapping {
+						comments,
+						..mapping
+					})
+					.map_err(|err| ParseError::new(line, text, err).into()),
+			);
+		}
+	}
+}
+
+/// An error reading a mapping file
+#[derive(Debug)]
+pub enum LoadError {
+	/// The file couldn't be read
+	Io(io::Error),
+	/// The file contents were malformed
+	Parse(ParseError),
+}
+
+impl From<io::Error> for LoadError {
+	fn from(err: io::Error) -> Self {
+		LoadError::Io(err)
+	}
+}
+
+impl From<ParseError> for LoadError {
+	fn from(err: ParseError) -> Self {
+		LoadError::Parse(err)
+	}
+}
+
+impl std::fmt::Display for LoadError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			LoadError::Io(err) => write!(f, "Failed to read mapping file: {err}"),
+			LoadError::Parse(err) => err.fmt(f),
+		}
+	}
+}
+
+impl std::error::Error for LoadError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			LoadError::Io(err) => Some(err),
+			LoadError::Parse(_) => None,
+		}
+	}
+}
+
+/// An error writing a mapping file
+#[derive(Debug)]
+pub enum SaveError {
+	/// The file couldn't be written
+	Io(io::Error),
+	/// A mapping can't be written in a way that parses back to the same mapping
+	Invalid {
+		/// The user of the mapping
+		user: String,
+		/// What's wrong with the mapping
+		error: Error,
+	},
+}
+
+impl From<io::Error> for SaveError {
+	fn from(err: io::Error) -> Self {
+		SaveError::Io(err)
+	}
+}
+
+impl std::fmt::Display for SaveError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			SaveError::Io(err) => write!(f, "Failed to write mapping file: {err}"),
+			SaveError::Invalid { user, error } => {
+				write!(f, "Mapping for {user:?} can't be written: {error}")
+			}
+		}
+	}
+}
+
+impl std::error::Error for SaveError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			SaveError::Io(err) => Some(err),
+			SaveError::Invalid { error, .. } => Some(error),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::path::PathBuf;
+
+	use crate::{Algorithm, Error, LoadError, Mapping, MappingFile, SaveError, SaveOptions};
+	type BoxError = Box<dyn std::error::Error>;
+
+	#[test]
+	fn from_reader() -> Result<(), BoxError> {
+		let file = "# comment\nalice:h,p,es256,+presence\r\n\nbob\n# end\n";
+		assert_eq!(
+			MappingFile::from_reader(file.as_bytes())?,
+			file.parse::<MappingFile>()?
+		);
+		let mut lines = Mapping::parse_lines("alice\n\nbob:h,p\ncarol".as_bytes());
+		assert_eq!(lines.next().unwrap()?.user, "alice");
+		match lines.next() {
+			Some(Err(LoadError::Parse(err))) => {
+				assert_eq!(
+					(err.line, err.error),
+					(3, Error::KindMissing { key_index: 0 })
+				)
+			}
+			other => panic!("expected parse error, got {other:?}"),
+		}
+		assert_eq!(lines.next().unwrap()?.user, "carol");
+		assert!(lines.next().is_none());
+		Ok(())
+	}
+
+	/// A path in the temporary directory that's unique to the given test
+	fn temp_path(test: &str) -> PathBuf {
+		std::env::temp_dir().join(format!("pam-u2f-mapping-{}-{test}", std::process::id()))
+	}
+
+	#[test]
+	fn load_save() -> Result<(), BoxError> {
+		let path = temp_path("load_save");
+		let file: MappingFile = "alice:h,p,es256,+presence\nbob".parse()?;
+		file.save(&path)?;
+		assert_eq!(MappingFile::load(&path)?, file);
+		std::fs::remove_file(&path)?;
+		assert!(matches!(MappingFile::load(&path), Err(LoadError::Io(_))));
+		let mut file = file;
+		file.get_mut("alice").unwrap().keys[0]
+			.flags
+			.other
+			.push("a+b".into());
+		assert!(matches!(
+			file.save(&path),
+			Err(SaveError::Invalid { user, error: Error::BadFlags }) if user == "alice"
+		));
+		assert!(!path.exists());
+		let mut file: MappingFile = "alice:h,p,es256,".parse()?;
+		file.mappings[0].keys[0].handle = "a,b".into();
+		let err = file.save(&path).unwrap_err();
+		assert!(matches!(
+			err,
+			SaveError::Invalid {
+				error: Error::BadField { .. },
+				..
+			}
+		));
+		file.mappings[0].keys[0].handle = "h".into();
+		file.mappings[0].keys[0].kind = Algorithm::Other("x:y".into());
+		let err = file.save(&path).unwrap_err();
+		assert!(matches!(
+			err,
+			SaveError::Invalid {
+				error: Error::BadKind,
+				..
+			}
+		));
+		file.mappings[0].keys.clear();
+		file.mappings[0].user = "bob:evil,p,es256,\nroot".into();
+		let err = file.save(&path).unwrap_err();
+		assert!(matches!(
+			err,
+			SaveError::Invalid {
+				error: Error::InvalidUser,
+				..
+			}
+		));
+		assert!(!path.exists());
+		Ok(())
+	}
+
+	#[test]
+	fn save_replaces_atomically() -> Result<(), BoxError> {
+		let path = temp_path("save_replaces_atomically");
+		std::fs::write(&path, "old")?;
+		let mut permissions = std::fs::metadata(&path)?.permissions();
+		permissions.set_readonly(true);
+		std::fs::set_permissions(&path, permissions)?;
+		MappingFile::default().save(&path)?;
+		assert_eq!(std::fs::read_to_string(&path)?, "");
+		assert!(std::fs::metadata(&path)?.permissions().readonly());
+		MappingFile::default().save(&path)?;
+		let dir = path.parent().unwrap();
+		let prefix = format!(".{}.tmp", path.file_name().unwrap().to_string_lossy());
+		assert!(!std::fs::read_dir(dir)?.any(|entry| entry
+			.unwrap()
+			.file_name()
+			.to_string_lossy()
+			.starts_with(&prefix)));
+		std::fs::remove_file(&path)?;
+		std::fs::remove_file(path.with_extension("bak"))?;
+		Ok(())
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn save_keeps_mode() -> Result<(), BoxError> {
+		use std::os::unix::fs::PermissionsExt;
+		let path = temp_path("save_keeps_mode");
+		std::fs::write(&path, "")?;
+		std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640))?;
+		let options = SaveOptions::new().backup(false);
+		"alice".parse::<MappingFile>()?.save_with(&path, &options)?;
+		assert_eq!(
+			std::fs::metadata(&path)?.permissions().mode() & 0o777,
+			0o640
+		);
+		std::fs::remove_file(&path)?;
+		Ok(())
+	}
+
+	#[test]
+	fn passwd() {
+		let passwd = "root:x:0:0:root:/root:/bin/sh\n\
+			alice:x:1000:1000:Alice,,,:/home/alice:/bin/bash\n\
+			nohome:x:1001:1001::\n";
+		assert_eq!(super::passwd_home(passwd, "alice"), Some("/home/alice"));
+		assert_eq!(super::passwd_home(passwd, "ali"), None);
+		assert_eq!(super::passwd_home(passwd, "nohome"), None);
+		assert_eq!(super::passwd_home(passwd, "bob"), None);
+	}
+
+	#[test]
+	#[cfg(unix)]
+	fn default_path() {
+		let path = MappingFile::default_path(Some("root")).unwrap();
+		assert!(path.ends_with(".config/Yubico/u2f_keys"));
+		assert!(path.is_absolute());
+		assert_eq!(MappingFile::default_path(Some("no such user")), None);
+	}
+
+	#[test]
+	fn save_concurrently() -> Result<(), BoxError> {
+		let path = temp_path("save_concurrently");
+		std::fs::write(&path, "")?;
+		std::thread::scope(|scope| {
+			let threads = (0..8)
+				.map(|i| {
+					let path = &path;
+					scope.spawn(move || -> Result<(), SaveError> {
+						let file: MappingFile = format!("user{i}").parse().unwrap();
+						(0..20).try_for_each(|_| file.save(path))
+					})
+				})
+				.collect::<Vec<_>>();
+			threads
+				.into_iter()
+				.try_for_each(|thread| thread.join().unwrap())
+		})?;
+		assert!(std::fs::read_to_string(&path)?.starts_with("user"));
+		std::fs::remove_file(&path)?;
+		std::fs::remove_file(path.with_extension("bak"))?;
+		Ok(())
+	}
+
+	#[test]
+	fn backup() -> Result<(), BoxError> {
+		let path = temp_path("backup");
+		let backup = path.with_extension("bak");
+		let file: MappingFile = "alice".parse()?;
+		file.save(&path)?;
+		assert!(!backup.exists());
+		std::fs::write(&path, "old")?;
+		file.save(&path)?;
+		assert_eq!(std::fs::read_to_string(&backup)?, "old");
+		file.save_with(&path, &SaveOptions::new().backup(false))?;
+		assert_eq!(std::fs::read_to_string(&backup)?, "old");
+		file.save(&path)?;
+		assert_eq!(std::fs::read_to_string(&backup)?, "alice\n");
+		std::fs::remove_file(&path)?;
+		std::fs::remove_file(&backup)?;
+		Ok(())
+	}
+}
@@ -0,0 +1,537 @@
+// Copyright © Amanda Graven 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! JSON import and export of mapping files. The reader and writer are implemented here rather
+//! than pulling in a JSON library, as the schema is small and fixed.
+
+use alloc::borrow::ToOwned;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use crate::{Error, Flags, Key, Mapping, MappingFile};
+
+impl MappingFile {
+	/// Encodes the mapping file as compact JSON. The field names match those of
+	/// [`MappingFile`], [`Mapping`] and [`Key`], with `kind` as its string form and `flags` as
+	/// an array of flag names, e.g.:
+	///
+	/// ```json
+	/// {"mappings":[{"user":"alice","keys":[{"handle":"…","public":"…","kind":"es256","flags":["presence"]}]}]}
+	/// ```
+	///
+	/// Comments are written as arrays of lines in `comments` and `trailing_comments`, which are
+	/// left out when empty. Decoding the result with [`MappingFile::from_json`] gives back an
+	/// identical file.
+	pub fn to_json(&self) -> String {
+		let mut out = String::new();
+		out.push_str("{\"mappings\":[");
+		for (i, mapping) in self.mappings.iter().enumerate() {
+			if i > 0 {
+				out.push(',');
+			}
+			out.push_str("{\"user\":");
+			write_string(&mut out, &mapping.user);
+			out.push_str(",\"keys\":[");
+			for (i, key) in mapping.keys.iter().enumerate() {
+				if i > 0 {
+					out.push(',');
+				}
+				out.push_str("{\"handle\":");
+				write_string(&mut out, &key.handle);
+				out.push_str(",\"public\":");
+				write_string(&mut out, &key.public);
+				out.push_str(",\"kind\":");
+				write_string(&mut out, key.kind.as_str());
+				out.push_str(",\"flags\":[");
+				for (i, flag) in key.flags.names().enumerate() {
+					if i > 0 {
+						out.push(',');
+					}
+					write_string(&mut out, flag);
+				}
+				out.push_str("]}");
+			}
+			out.push(']');
+			write_strings(&mut out, "comments", &mapping.comments);
+			out.push('}');
+		}
+		out.push(']');
+		write_strings(&mut out, "trailing_comments", &self.trailing_comments);
+		out.push('}');
+		out
+	}
+
+	/// Decodes a mapping file from the JSON produced by [`MappingFile::to_json`]. Unknown object
+	/// fields are ignored, and the comment fields may be left out.
+	///
+	/// Every mapping is checked with [`Mapping::check`], so that the decoded file formats to the
+	/// same mappings. A mapping that would not, e.g. because its username contains `:`, is
+	/// reported as [`JsonError::Invalid`].
+	pub fn from_json(s: &str) -> Result<MappingFile, JsonError> {
+		let mut parser = Parser { s, pos: 0 };
+		let value = parser.value(0)?;
+		parser.skip_whitespace();
+		if parser.pos != s.len() {
+			return Err(JsonError::Syntax { offset: parser.pos });
+		}
+		let mappings: Vec<Mapping> = value
+			.field("mappings")?
+			.array("mappings")?
+			.iter()
+			.map(|mapping| {
+				let keys = mapping.field("keys")?.array("keys")?;
+				Ok(Mapping {
+					user: mapping.field("user")?.string("user")?.to_owned(),
+					keys: keys.iter().map(key).collect::<Result<_, _>>()?,
+					comments: strings(mapping, "comments")?,
+				})
+			})
+			.collect::<Result<_, _>>()?;
+		for (index, mapping) in mappings.iter().enumerate() {
+			mapping
+				.check()
+				.map_err(|error| JsonError::Invalid { index, error })?;
+		}
+		Ok(MappingFile {
+			mappings,
+			trailing_comments: strings(&value, "trailing_comments")?,
+		})
+	}
+}
+
+/// Decodes an optional field holding an array of strings, which is empty if missing
+fn strings(value: &Value, field: &'static str) -> Result<Vec<String>, JsonError> {
+	match value.field(field) {
+		Ok(array) => array
+			.array(field)?
+			.iter()
+			.map(|s| s.string(field).map(str::to_owned))
+			.collect(),
+		Err(JsonError::MissingField { .. }) => Ok(Vec::new()),
+		Err(err) => Err(err),
+	}
+}
+
+/// Writes `,"field":[...]` if `strings` isn't empty
+fn write_strings(out: &mut String, field: &str, strings: &[String]) {
+	if strings.is_empty() {
+		return;
+	}
+	let _ = write!(out, ",\"{field}\":[");
+	for (i, s) in strings.iter().enumerate() {
+		if i > 0 {
+			out.push(',');
+		}
+		write_string(out, s);
+	}
+	out.push(']');
+}
+
+/// Decodes a single key object
+fn key(value: &Value) -> Result<Key, JsonError> {
+	let Ok(kind) = value.field("kind")?.string("kind")?.parse();
+	let flags = value
+		.field("flags")?
+		.array("flags")?
+		.iter()
+		.map(|flag| flag.string("flags"))
+		.collect::<Result<Vec<_>, _>>()?;
+	Ok(Key {
+		handle: value.field("handle")?.string("handle")?.to_owned(),
+		public: value.field("public")?.string("public")?.to_owned(),
+		kind,
+		flags: Flags::from_names(flags),
+	})
+}
+
+/// Writes a quoted JSON string, escaping as necessary
+fn write_string(out: &mut String, s: &str) {
+	out.push('"');
+	for c in s.chars() {
+		match c {
+			'"' => out.push_str("\\\""),
+			'\\' => out.push_str("\\\\"),
+			'\n' => out.push_str("\\n"),
+			'\r' => out.push_str("\\r"),
+			'\t' => out.push_str("\\t"),
+			c if u32::from(c) < 0x20 => {
+				let _ = write!(out, "\\u{:04x}", u32::from(c));
+			}
+			c => out.push(c),
+		}
+	}
+	out.push('"');
+}
+
+/// An error decoding a mapping file from JSON
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JsonError {
+	/// The input isn't valid JSON
+	Syntax {
+		/// The byte offset the error was found at
+		offset: usize,
+	},
+	/// An object lacks a required field
+	MissingField {
+		/// The name of the missing field
+		field: &'static str,
+	},
+	/// A field has the wrong type
+	InvalidType {
+		/// The name of the field
+		field: &'static str,
+	},
+	/// A mapping can't be written to a mapping file, see [`Mapping::check`]
+	Invalid {
+		/// The position of the mapping in `mappings`
+		index: usize,
+		/// What's wrong with the mapping
+		error: Error,
+	},
+}
+
+impl core::fmt::Display for JsonError {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		match self {
+			JsonError::Syntax { offset } => write!(f, "Invalid JSON at byte {offset}"),
+			JsonError::MissingField { field } => write!(f, "Missing field `{field}`"),
+			JsonError::InvalidType { field } => write!(f, "Field `{field}` has the wrong type"),
+			JsonError::Invalid { index, error } => {
+				write!(f, "Mapping {index} can't be written: {error}")
+			}
+		}
+	}
+}
+
+#[cfg(any(feature = "std", test))]
+impl std::error::Error for JsonError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			JsonError::Invalid { error, .. } => Some(error),
+			_ => None,
+		}
+	}
+}
+
+/// A parsed JSON value. Numbers are validated but not kept, as the schema has none.
+enum Value {
+	Null,
+	Bool,
+	Number,
+	String(String),
+	Array(Vec<Value>),
+	Object(Vec<(String, Value)>),
+}
+
+impl Value {
+	/// Looks up a field of an object
+	fn field(&self, field: &'static str) -> Result<&Value, JsonError> {
+		match self {
+			Value::Object(fields) => fields
+				.iter()
+				.find(|(name, _)| name == field)
+				.map(|(_, value)| value)
+				.ok_or(JsonError::MissingField { field }),
+			_ => Err(JsonError::InvalidType { field }),
+		}
+	}
+
+	fn array(&self, field: &'static str) -> Result<&[Value], JsonError> {
+		match self {
+			Value::Array(values) => Ok(values),
+			_ => Err(JsonError::InvalidType { field }),
+		}
+	}
+
+	fn string(&self, field: &'static str) -> Result<&str, JsonError> {
+		match self {
+			Value::String(s) => Ok(s),
+			_ => Err(JsonError::InvalidType { field }),
+		}
+	}
+}
+
+/// Recursive descent JSON parser
+struct Parser<'a> {
+	s: &'a str,
+	pos: usize,
+}
+
+impl Parser<'_> {
+	/// How deeply arrays and objects may nest, so hostile input can't overflow the stack
+	const MAX_DEPTH: usize = 64;
+
+	fn error(&self) -> JsonError {
+		JsonError::Syntax { offset: self.pos }
+	}
+
+	fn peek(&self) -> Option<u8> {
+		self.s.as_bytes().get(self.pos).copied()
+	}
+
+	fn skip_whitespace(&mut self) {
+		while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.peek() {
+			self.pos += 1;
+		}
+	}
+
+	/// Consumes `token` if the input continues with it
+	fn eat(&mut self, token: &str) -> bool {
+		let found = self.s[self.pos..].starts_with(token);
+		if found {
+			self.pos += token.len();
+		}
+		found
+	}
+
+	fn expect(&mut self, token: &str) -> Result<(), JsonError> {
+		self.skip_whitespace();
+		if self.eat(token) {
+			Ok(())
+		} else {
+			Err(self.error())
+		}
+	}
+
+	fn value(&mut self, depth: usize) -> Result<Value, JsonError> {
+		if depth > Self::MAX_DEPTH {
+			return Err(self.error());
+		}
+		self.skip_whitespace();
+		match self.peek() {
+			Some(b'{') => {
+				self.pos += 1;
+				let mut fields = Vec::new();
+				if !self.list_end(b'}') {
+					loop {
+						self.skip_whitespace();
+						let name = self.string()?;
+						self.expect(":")?;
+						fields.push((name, self.value(depth + 1)?));
+						if self.list_next(b'}')? {
+							break;
+						}
+					}
+				}
+				Ok(Value::Object(fields))
+			}
+			Some(b'[') => {
+				self.pos += 1;
+				let mut values = Vec::new();
+				if !self.list_end(b']') {
+					loop {
+						values.push(self.value(depth + 1)?);
+						if self.list_next(b']')? {
+							break;
+						}
+					}
+				}
+				Ok(Value::Array(values))
+			}
+			Some(b'"') => Ok(Value::String(self.string()?)),
+			Some(b'-' | b'0'..=b'9') => self.number(),
+			_ if self.eat("true") || self.eat("false") => Ok(Value::Bool),
+			_ if self.eat("null") => Ok(Value::Null),
+			_ => Err(self.error()),
+		}
+	}
+
+	/// Checks for the end of an empty array or object
+	fn list_end(&mut self, end: u8) -> bool {
+		self.skip_whitespace();
+		let found = self.peek() == Some(end);
+		if found {
+			self.pos += 1;
+		}
+		found
+	}
+
+	/// Consumes the separator after an element, returning `true` at the end of the list
+	fn list_next(&mut self, end: u8) -> Result<bool, JsonError> {
+		self.skip_whitespace();
+		match self.peek() {
+			Some(b',') => {
+				self.pos += 1;
+				Ok(false)
+			}
+			Some(c) if c == end => {
+				self.pos += 1;
+				Ok(true)
+			}
+			_ => Err(self.error()),
+		}
+	}
+
+	fn number(&mut self) -> Result<Value, JsonError> {
+		let digits = |p: &mut Self| {
+			let start = p.pos;
+			while let Some(b'0'..=b'9') = p.peek() {
+				p.pos += 1;
+			}
+			p.pos > start
+		};
+		self.eat("-");
+		if !self.eat("0") && !digits(self) {
+			return Err(self.error());
+		}
+		if self.eat(".") && !digits(self) {
+			return Err(self.error());
+		}
+		if matches!(self.peek(), Some(b'e' | b'E')) {
+			self.pos += 1;
+			let _ = self.eat("+") || self.eat("-");
+			if !digits(self) {
+				return Err(self.error());
+			}
+		}
+		Ok(Value::Number)
+	}
+
+	fn string(&mut self) -> Result<String, JsonError> {
+		if !self.eat("\"") {
+			return Err(self.error());
+		}
+		let mut out = String::new();
+		loop {
+			let rest = &self.s[self.pos..];
+			let Some(end) = rest.find(['"', '\\']) else {
+				return Err(JsonError::Syntax {
+					offset: self.s.len(),
+				});
+			};
+			if rest[..end].chars().any(|c| u32::from(c) < 0x20) {
+				return Err(self.error());
+			}
+			out.push_str(&rest[..end]);
+			self.pos += end + 1;
+			if rest.as_bytes()[end] == b'"' {
+				return Ok(out);
+			}
+			let escape = self.peek().ok_or_else(|| self.error())?;
+			self.pos += 1;
+			out.push(match escape {
+				b'"' => '"',
+				b'\\' => '\\',
+				b'/' => '/',
+				b'b' => '\u{8}',
+				b'f' => '\u{c}',
+				b'n' => '\n',
+				b'r' => '\r',
+				b't' => '\t',
+				b'u' => self.unicode_escape()?,
+				_ => return Err(self.error()),
+			});
+		}
+	}
+
+	/// Decodes the hex digits of a `\u` escape, including a following low surrogate
+	fn unicode_escape(&mut self) -> Result<char, JsonError> {
+		let high = self.hex4()?;
+		let code = if (0xd800..0xdc00).contains(&high) {
+			if !self.eat("\\u") {
+				return Err(self.error());
+			}
+			let low = self.hex4()?;
+			if !(0xdc00..0xe000).contains(&low) {
+				return Err(self.error());
+			}
+			0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+		} else {
+			high
+		};
+		char::from_u32(code).ok_or_else(|| self.error())
+	}
+
+	fn hex4(&mut self) -> Result<u32, JsonError> {
+		let digits = self
+			.s
+			.get(self.pos..self.pos + 4)
+			.ok_or_else(|| self.error())?;
+		if !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+			return Err(self.error());
+		}
+		self.pos += 4;
+		u32::from_str_radix(digits, 16).map_err(|_| self.error())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{Error, JsonError, MappingFile};
+	type BoxError = Box<dyn std::error::Error>;
+
+	#[test]
+	fn round_trip() -> Result<(), BoxError> {
+		let file: MappingFile =
+			"alice:h,p,es256,+pin+presence+x\n# bob's\nbob\n\"odd\\user\":h,p,eddsa,\n# end"
+				.parse()?;
+		let json = file.to_json();
+		assert!(json.starts_with(
+			r#"{"mappings":[{"user":"alice","keys":[{"handle":"h","public":"p","kind":"es256","flags":["pin","presence","x"]}]}"#
+		));
+		assert!(json.contains(r##""keys":[],"comments":["# bob's"]}"##));
+		assert!(json.ends_with(r##"],"trailing_comments":["# end"]}"##));
+		assert_eq!(MappingFile::from_json(&json)?, file);
+		assert_eq!(MappingFile::from_json(&json)?.to_string(), file.to_string());
+		Ok(())
+	}
+
+	#[test]
+	fn decode() -> Result<(), BoxError> {
+		let json = r#" { "version": 1.5e3, "mappings": [
+			{ "keys": [], "user": "æ😀\"x", "extra": [null, true, {}] }
+		] } "#;
+		assert_eq!(MappingFile::from_json(json)?.mappings[0].user, "æ😀\"x");
+		assert_eq!(
+			MappingFile::from_json(r#"{"mappings":[{"user":"alice"}]}"#),
+			Err(JsonError::MissingField { field: "keys" })
+		);
+		assert_eq!(
+			MappingFile::from_json(r#"{"mappings":{}}"#),
+			Err(JsonError::InvalidType { field: "mappings" })
+		);
+		assert_eq!(
+			MappingFile::from_json(r#"{"mappings":[]} x"#),
+			Err(JsonError::Syntax { offset: 16 })
+		);
+		let deep = "[".repeat(1000);
+		assert!(matches!(
+			MappingFile::from_json(&deep),
+			Err(JsonError::Syntax { .. })
+		));
+		Ok(())
+	}
+
+	#[test]
+	fn unwritable() {
+		let json =
+			r#"{"mappings":[{"user":"bob","keys":[]},{"user":"bob:h,p,es256,\nroot","keys":[]}]}"#;
+		assert_eq!(
+			MappingFile::from_json(json),
+			Err(JsonError::Invalid {
+				index: 1,
+				error: Error::InvalidUser
+			})
+		);
+		let json = r#"{"mappings":[{"user":"bob","keys":[{"handle":"h","public":"p","kind":"es256","flags":["pin","a+b"]}]}]}"#;
+		assert_eq!(
+			MappingFile::from_json(json),
+			Err(JsonError::Invalid {
+				index: 0,
+				error: Error::BadFlags
+			})
+		);
+		let json = r#"{"mappings":[{"user":"bob","keys":[{"handle":"h,x","public":"p","kind":"es256","flags":[]}]}]}"#;
+		assert!(matches!(
+			MappingFile::from_json(json),
+			Err(JsonError::Invalid {
+				index: 0,
+				error: Error::BadField { .. }
+			})
+		));
+	}
+}
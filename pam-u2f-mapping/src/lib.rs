@@ -6,132 +6,1093 @@
 
 //! Dead simple parser and formatter for mapping files generated by
 //! `pamu2fcfg(1)`.
+//!
+//! The `std` feature is enabled by default. Without it the crate is `no_std` and only needs
+//! `alloc`; reading and writing files is then unavailable. The optional `json` feature adds
+//! conversion to and from JSON, and `cose` adds [`Key::validate_public_key`] for checking key
+//! material against its algorithm.
 
+// Tests always have the standard library, so they can use it without the `std` feature
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 #![warn(missing_docs)]
 
-use std::str::FromStr;
+extern crate alloc;
+
+mod algorithm;
+mod base64;
+mod borrowed;
+mod builder;
+#[cfg(feature = "cose")]
+mod cose;
+mod diff;
+mod flags;
+mod format;
+#[cfg(feature = "std")]
+mod io;
+#[cfg(feature = "json")]
+mod json;
+mod pretty;
+mod resolve;
+mod transaction;
+mod validate;
+
+use alloc::borrow::ToOwned;
+use alloc::collections::BTreeSet;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem;
+use core::ops::Range;
+use core::str::{FromStr, Utf8Error};
+
+pub use algorithm::Algorithm;
+pub use borrowed::{KeyRef, MappingRef};
+pub use builder::KeyBuilder;
+#[cfg(feature = "cose")]
+pub use cose::ValidationError;
+pub use diff::{MappingDiff, UserDiff};
+pub use flags::{FlagBits, Flags};
+pub use format::{Format, Reenrollment};
+#[cfg(feature = "std")]
+pub use io::{LoadError, ParseLines, SaveError, SaveOptions};
+#[cfg(feature = "json")]
+pub use json::JsonError;
+pub use resolve::Resolution;
+pub use transaction::Transaction;
+pub use validate::{UsernameProblem, ValidationWarning, WarningKind};
 
 /// Represents the contents of a mapping file.
-#[derive(Clone, Debug)]
+///
+/// When parsing, blank lines are skipped, and lines starting with `#` are kept as comments.
+/// Comment lines are attached to the mapping below them, in [`Mapping::comments`], and comments
+/// after the last mapping are kept in [`MappingFile::trailing_comments`]. Formatting writes each
+/// mapping's comments above it in their original order, so comments move along with their
+/// mapping when mappings are sorted or removed. Blank lines are not kept.
+///
+/// Apart from blank lines, formatting a parsed file reproduces it exactly, byte for byte. Use
+/// [`MappingFile::normalize`] to rewrite it into a canonical form.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct MappingFile {
 	/// The list of mapping entries in the file
 	pub mappings: Vec<Mapping>,
+	/// Comment lines after the last mapping, or in a file without mappings
+	pub trailing_comments: Vec<String>,
 }
 
 /// The list of keys associated with a given username. Corresponds to one line in the mapping file
-#[derive(Clone, Debug)]
+///
+/// Mappings are ordered by username, with their keys and then their comments as tie breakers.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Mapping {
 	/// The username the mapping applies to
 	pub user: String,
 	/// The list of keys associated with the user
 	pub keys: Vec<Key>,
+	/// The comment lines directly above the mapping, as written including the `#`. They aren't
+	/// part of the mapping line, so they're only written when formatting a [`MappingFile`].
+	pub comments: Vec<String>,
 }
 
 /// A key entry in a mapping file. Corresponds to one colon (:) separated entry in a mapping line.
-#[derive(Clone, Debug)]
+///
+/// Keys compare equal when all their fields do. Since [`Flags`] is typed, the order the known
+/// flags were written in doesn't matter, but unknown flags must appear in the same order.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Key {
 	/// The key handle
 	pub handle: String,
 	/// The public key data
 	pub public: String,
 	/// The key algorithm
-	pub kind: String,
+	pub kind: Algorithm,
 	/// Flags for the key
-	pub flags: Vec<String>,
+	pub flags: Flags,
+}
+
+impl Key {
+	/// Creates a key from the credential returned when enrolling an authenticator, encoding the
+	/// credential ID and public key as base64 the same way `pamu2fcfg` does.
+	///
+	/// `public_key` is the key in the form libfido2 returns it from `fido_cred_pubkey_ptr`, which
+	/// is what `pamu2fcfg` stores. This is not a COSE key: for `es256` it's the 64 byte
+	/// concatenation of the x and y coordinates, and for `eddsa` the 32 byte public key.
+	pub fn from_credential(
+		credential_id: &[u8],
+		public_key: &[u8],
+		algorithm: Algorithm,
+		flags: Flags,
+	) -> Key {
+		Key {
+			handle: base64::encode(credential_id),
+			public: base64::encode(public_key),
+			kind: algorithm,
+			flags,
+		}
+	}
+
+	/// The flags set on the key
+	pub fn flags(&self) -> &Flags {
+		&self.flags
+	}
+
+	/// The known flags of the key as a bit set
+	pub fn flag_bits(&self) -> FlagBits {
+		self.flags.bits()
+	}
+
+	/// Whether the key requires user presence
+	pub fn requires_presence(&self) -> bool {
+		self.flags.presence
+	}
+
+	/// Whether the key requires the PIN to be entered
+	pub fn requires_pin(&self) -> bool {
+		self.flags.pin
+	}
+
+	/// Whether the key requires user verification
+	pub fn requires_verification(&self) -> bool {
+		self.flags.verification
+	}
+
+	/// Sets or clears the `presence` flag, leaving other flags as they are
+	pub fn set_presence(&mut self, presence: bool) {
+		self.flags.presence = presence;
+	}
+
+	/// Sets or clears the `pin` flag, leaving other flags as they are
+	pub fn set_pin(&mut self, pin: bool) {
+		self.flags.pin = pin;
+	}
+
+	/// Sets or clears the `verification` flag, leaving other flags as they are
+	pub fn set_verification(&mut self, verification: bool) {
+		self.flags.verification = verification;
+	}
+
+	/// A short description of the key for logs and listings, e.g. `es256 owBYwWdl… +presence`.
+	/// Only the first few characters of the handle are included, which is usually enough to
+	/// tell a user's keys apart but doesn't identify a key on its own. Use [`Key::handle`] for
+	/// that, and the `Display` impl for the full mapping file form.
+	pub fn summary(&self) -> String {
+		const HANDLE_LEN: usize = 8;
+		let mut summary = format!("{} ", self.kind);
+		let mut chars = self.handle.chars();
+		summary.extend(chars.by_ref().take(HANDLE_LEN));
+		if chars.next().is_some() {
+			summary.push('…');
+		}
+		let flags = format!("{}", self.flags);
+		if !flags.is_empty() {
+			summary.push(' ');
+			summary.push_str(&flags);
+		}
+		summary
+	}
+
+	/// Decodes the base64 key handle into a newly allocated buffer. Returns
+	/// [`Error::InvalidBase64`] if the handle is malformed.
+	///
+	/// Both the standard and the URL-safe alphabet are accepted, padding is required. Formatting
+	/// a key writes the field as it is stored, so it's never re-encoded. `pamu2fcfg` uses the
+	/// standard alphabet.
+	pub fn handle_bytes(&self) -> Result<Vec<u8>, Error> {
+		base64::decode(&self.handle).ok_or(Error::InvalidBase64 {
+			field: Field::Handle,
+		})
+	}
+
+	/// Checks that the key formats to something that parses back to the same key. Fails with
+	/// [`Error::BadField`] if the handle or public key contains `,`, `:` or a line break,
+	/// [`Error::BadKind`] if an unknown algorithm does or is the name of a known one, and as
+	/// [`Flags::check`] for the flags.
+	///
+	/// The key data isn't required to be valid base64, see [`MappingFile::parse_strict`] for
+	/// that.
+	pub fn check(&self) -> Result<(), Error> {
+		for (field, data) in [(Field::Handle, &self.handle), (Field::Public, &self.public)] {
+			if is_unwritable(data) {
+				return Err(Error::BadField { field });
+			}
+		}
+		if let Algorithm::Other(name) = &self.kind {
+			let Ok(parsed) = name.parse::<Algorithm>();
+			if is_unwritable(name) || parsed != self.kind {
+				return Err(Error::BadKind);
+			}
+		}
+		self.flags.check()
+	}
+
+	/// Decodes the base64 public key into a newly allocated buffer. Returns
+	/// [`Error::InvalidBase64`] if the public key is malformed. The accepted encodings are the
+	/// same as for [`Key::handle_bytes`].
+	pub fn public_bytes(&self) -> Result<Vec<u8>, Error> {
+		base64::decode(&self.public).ok_or(Error::InvalidBase64 {
+			field: Field::Public,
+		})
+	}
+}
+
+impl MappingFile {
+	/// Parses a mapping file, additionally checking that every key handle and public key is valid
+	/// base64, in either the standard or the URL-safe alphabet, that no line has an empty
+	/// username, and that no key has fields after its flags. Parsing with [`FromStr`] does not perform
+	/// these checks, but [`MappingFile::validate`] reports empty usernames.
+	pub fn parse_strict(s: &str) -> Result<MappingFile, ParseError> {
+		MappingFile::parse(s, true)
+	}
+
+	/// Parses every line that can be parsed, collecting errors for the rest instead of stopping
+	/// at the first one. Each error carries the line number it occurred on. Comments above a line
+	/// that fails to parse are dropped along with it.
+	pub fn parse_collecting(s: &str) -> (MappingFile, Vec<ParseError>) {
+		let mut file = MappingFile::default();
+		let mut errors = Vec::new();
+		let mut comments = Vec::new();
+		for line in lines(s) {
+			match line {
+				Line::Comment(text) => comments.push(text.to_owned()),
+				Line::Entry(number, text) => match Mapping::parse_spanned(text, false) {
+					Ok(mut mapping) => {
+						mapping.comments = mem::take(&mut comments);
+						file.mappings.push(mapping);
+					}
+					Err(err) => {
+						comments.clear();
+						errors.push(ParseError::new(number, text, err));
+					}
+				},
+			}
+		}
+		file.trailing_comments = comments;
+		(file, errors)
+	}
+
+	/// Parses a mapping file one line at a time, for tools that decide for themselves how to treat
+	/// each line. Yields the line number, starting at 1, the text of the line and the result of
+	/// parsing it. Blank lines and comments are skipped, and aren't attached to the mappings.
+	pub fn entries(s: &str) -> impl Iterator<Item = (usize, &str, Result<Mapping, Error>)> {
+		entry_lines(s).map(|(number, text)| (number, text, text.parse()))
+	}
+
+	/// The number of mappings in the file
+	pub fn len(&self) -> usize {
+		self.mappings.len()
+	}
+
+	/// Whether the file contains no mappings
+	pub fn is_empty(&self) -> bool {
+		self.mappings.is_empty()
+	}
+
+	/// The number of keys in the file, across all users
+	pub fn total_keys(&self) -> usize {
+		self.mappings.iter().map(Mapping::key_count).sum()
+	}
+
+	/// Iterates over the users that are mapped without any keys. Unless the PAM module is
+	/// configured with `nouserok`, such users can't log in at all.
+	pub fn users_without_keys(&self) -> impl Iterator<Item = &str> {
+		self.mappings
+			.iter()
+			.filter(|mapping| mapping.is_empty())
+			.map(|mapping| mapping.user.as_str())
+	}
+
+	/// Finds the first mapping for the given user. This is the entry PAM uses if the file lists
+	/// the same user more than once.
+	pub fn get(&self, user: &str) -> Option<&Mapping> {
+		self.mappings.iter().find(|mapping| mapping.user == user)
+	}
+
+	/// Builds a map from usernames to mappings, for callers looking up many users. If the file
+	/// lists a user more than once, the first mapping wins, as with [`MappingFile::get`] and PAM.
+	#[cfg(feature = "std")]
+	pub fn index(&self) -> std::collections::HashMap<&str, &Mapping> {
+		let mut index = std::collections::HashMap::with_capacity(self.mappings.len());
+		for mapping in &self.mappings {
+			index.entry(mapping.user.as_str()).or_insert(mapping);
+		}
+		index
+	}
+
+	/// Finds the first mapping for the given user, allowing it to be modified
+	pub fn get_mut(&mut self, user: &str) -> Option<&mut Mapping> {
+		self.mappings
+			.iter_mut()
+			.find(|mapping| mapping.user == user)
+	}
+
+	/// Whether the file contains a mapping for the given user
+	pub fn contains_user(&self, user: &str) -> bool {
+		self.get(user).is_some()
+	}
+
+	/// Iterates over every key in the file, along with the user it's mapped to
+	pub fn keys(&self) -> impl Iterator<Item = (&str, &Key)> {
+		self.mappings.iter().flat_map(|mapping| {
+			let user = mapping.user.as_str();
+			mapping.keys.iter().map(move |key| (user, key))
+		})
+	}
+
+	/// Iterates over every key in the file, along with the user it's mapped to, allowing the
+	/// keys to be modified
+	pub fn keys_mut(&mut self) -> impl Iterator<Item = (&str, &mut Key)> {
+		self.mappings.iter_mut().flat_map(|mapping| {
+			let user = mapping.user.as_str();
+			mapping.keys.iter_mut().map(move |key| (user, key))
+		})
+	}
+
+	/// Finds the first key with the given handle in the whole file, along with the user it's
+	/// mapped to. Useful for revoking a key when only its handle is known.
+	pub fn find_by_handle(&self, handle: &str) -> Option<(&str, &Key)> {
+		self.keys().find(|(_, key)| key.handle == handle)
+	}
+
+	/// Finds the first mapping for the given user, ignoring case. Use this when accounts are
+	/// resolved case-insensitively, e.g. by some LDAP or Active Directory NSS backends. The lookup
+	/// methods without the `_ci` suffix compare usernames exactly, as the PAM module does.
+	///
+	/// Names are compared by their Unicode lowercase mapping, without regard to locale. This means
+	/// e.g. the Turkish dotted and dotless i are distinct, and `ß` doesn't match `ss`.
+	pub fn get_ci(&self, user: &str) -> Option<&Mapping> {
+		self.mappings
+			.iter()
+			.find(|mapping| eq_ignore_case(&mapping.user, user))
+	}
+
+	/// Whether the file contains a mapping for the given user, ignoring case. See
+	/// [`MappingFile::get_ci`] for how names are compared.
+	pub fn contains_user_ci(&self, user: &str) -> bool {
+		self.get_ci(user).is_some()
+	}
+
+	/// Removes every key for which `f` returns `false`, e.g. to revoke a lost key from all users
+	/// at once. `f` is called with the user each key is mapped to. Returns the number of keys
+	/// removed.
+	///
+	/// With [`EmptyUsers::Remove`], users left without any keys are removed as well. Users that
+	/// had no keys to begin with are always kept.
+	pub fn retain_keys(
+		&mut self,
+		empty_users: EmptyUsers,
+		mut f: impl FnMut(&str, &Key) -> bool,
+	) -> usize {
+		let mut removed = 0;
+		self.mappings.retain_mut(|mapping| {
+			let before = mapping.keys.len();
+			mapping.keys.retain(|key| f(&mapping.user, key));
+			removed += before - mapping.keys.len();
+			!(empty_users == EmptyUsers::Remove && before > 0 && mapping.keys.is_empty())
+		});
+		removed
+	}
+
+	/// Splits the file into one file per user, e.g. for distributing fragments to
+	/// `/etc/u2f_mappings.d/<user>`. Yields each username with the contents of a file holding just
+	/// that user's mapping line, newline included. Comments aren't included.
+	///
+	/// If a user is listed more than once, only the first mapping is used, as that's the one PAM
+	/// uses. The result of [`MappingFile::merge`]-ing the fragments back together is then the same
+	/// as far as PAM is concerned.
+	pub fn split(&self) -> impl Iterator<Item = (&str, String)> {
+		let mut seen = BTreeSet::new();
+		self.mappings
+			.iter()
+			.filter(move |mapping| seen.insert(mapping.user.as_str()))
+			.map(|mapping| (mapping.user.as_str(), format!("{mapping}\n")))
+	}
+
+	/// Returns the mapping for the given user, adding one with no keys at the end of the file if
+	/// the user isn't present yet. Fails with [`Error::InvalidUser`] if the username wouldn't
+	/// parse back the same, see [`Mapping::check_user`].
+	pub fn add_user(&mut self, user: impl Into<String>) -> Result<&mut Mapping, Error> {
+		let user = user.into();
+		Mapping::check_user(&user)?;
+		Ok(self.entry(user))
+	}
+
+	/// Returns the first mapping for the given user, adding one if needed, without checking the
+	/// username
+	fn entry(&mut self, user: String) -> &mut Mapping {
+		let index = match self.position(&user) {
+			Some(index) => index,
+			None => {
+				self.mappings.push(Mapping {
+					user,
+					keys: Vec::new(),
+					comments: Vec::new(),
+				});
+				self.mappings.len() - 1
+			}
+		};
+		&mut self.mappings[index]
+	}
+
+	/// Replaces every key of the first mapping for the given user, adding the user at the end of
+	/// the file if needed. Returns whether the user was already present, or
+	/// [`Error::InvalidUser`] as with [`MappingFile::add_user`].
+	pub fn set_user_keys(
+		&mut self,
+		user: &str,
+		keys: impl IntoIterator<Item = Key>,
+	) -> Result<bool, Error> {
+		let existed = self.contains_user(user);
+		self.add_user(user)?.set_keys(keys);
+		Ok(existed)
+	}
+
+	/// Removes the first mapping for the given user and returns it
+	pub fn remove_user(&mut self, user: &str) -> Option<Mapping> {
+		let index = self.position(user)?;
+		Some(self.mappings.remove(index))
+	}
+
+	/// Renames the first mapping for `from` to `to`. Returns `false` and leaves the file
+	/// unchanged if `from` isn't present, `to` is already taken, or `to` isn't a username that
+	/// can be written to the file, see [`Mapping::check_user`].
+	pub fn rename_user(&mut self, from: &str, to: &str) -> bool {
+		if self.contains_user(to) || Mapping::check_user(to).is_err() {
+			return false;
+		}
+		match self.get_mut(from) {
+			Some(mapping) => {
+				mapping.user = to.to_owned();
+				true
+			}
+			None => false,
+		}
+	}
+
+	/// Merges the mappings from another file into this one. Keys for users already present are
+	/// appended to the first mapping for that user, other users are added at the end of the file
+	/// along with their comments. Trailing comments of `other` are appended to those of this file.
+	///
+	/// Whether a key counts as a duplicate is decided by `strategy`, and only ever within a single
+	/// user: if the same handle is mapped for two different usernames, both keep it.
+	pub fn merge(&mut self, other: MappingFile, strategy: MergeStrategy) {
+		for mapping in other.mappings {
+			let new = !self.contains_user(&mapping.user);
+			let target = self.entry(mapping.user);
+			if new {
+				target.comments = mapping.comments;
+			}
+			for key in mapping.keys {
+				if strategy == MergeStrategy::SkipDuplicates
+					&& target.find_key(&key.handle).is_some()
+				{
+					continue;
+				}
+				target.add_key(key);
+			}
+		}
+		self.trailing_comments.extend(other.trailing_comments);
+	}
+
+	/// Rewrites the file into a canonical form, so that files with the same content format
+	/// identically. Parsing never does this on its own.
+	///
+	/// - Whitespace around key fields is removed. Usernames are left as they are, as PAM
+	///   compares them exactly: trimming could make a mapping that never matched shadow the one
+	///   PAM uses. [`MappingFile::validate`] warns about such usernames instead.
+	/// - Mappings are sorted by username, byte-wise. The sort is stable, so if a user is listed
+	///   more than once, the entry PAM uses stays first.
+	/// - Keys that are identical to an earlier key of the same user are removed. The order of the
+	///   remaining keys is kept, as it's the order PAM tries them in.
+	/// - Repeated flags are removed, keeping the first occurrence. Flags are written in the order
+	///   `presence`, `pin`, `verification`, followed by unknown flags, rather than the order they
+	///   were parsed in.
+	pub fn normalize(&mut self) {
+		for mapping in &mut self.mappings {
+			for key in &mut mapping.keys {
+				trim_in_place(&mut key.handle);
+				trim_in_place(&mut key.public);
+				if let Algorithm::Other(name) = &key.kind {
+					let Ok(kind) = name.trim().parse();
+					key.kind = kind;
+				}
+				let mut seen = Vec::new();
+				key.flags.other.retain(|flag| {
+					let new = !seen.contains(flag);
+					seen.push(flag.clone());
+					new
+				});
+				key.flags.forget_order();
+			}
+			let mut keys = Vec::with_capacity(mapping.keys.len());
+			for key in mapping.keys.drain(..) {
+				if !keys.contains(&key) {
+					keys.push(key);
+				}
+			}
+			mapping.keys = keys;
+		}
+		self.mappings.sort_by(|a, b| a.user.cmp(&b.user));
+	}
+
+	/// The index of the first mapping for the given user
+	fn position(&self, user: &str) -> Option<usize> {
+		self.mappings
+			.iter()
+			.position(|mapping| mapping.user == user)
+	}
+
+	fn parse(s: &str, strict: bool) -> Result<MappingFile, ParseError> {
+		let mut file = MappingFile::default();
+		let mut comments = Vec::new();
+		for line in lines(s) {
+			match line {
+				Line::Comment(text) => comments.push(text.to_owned()),
+				Line::Entry(number, text) => {
+					let mut mapping = Mapping::parse_spanned(text, strict)
+						.map_err(|err| ParseError::new(number, text, err))?;
+					mapping.comments = mem::take(&mut comments);
+					file.mappings.push(mapping);
+				}
+			}
+		}
+		file.trailing_comments = comments;
+		Ok(file)
+	}
+}
+
+/// Whether [`MappingFile::retain_keys`] removes users that are left without any keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmptyUsers {
+	/// Keep users even if all their keys were removed
+	Keep,
+	/// Remove users whose keys were all removed
+	Remove,
+}
+
+/// How [`MappingFile::merge`] treats keys that the user already has
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergeStrategy {
+	/// Skip keys whose handle is already mapped for the user, including keys added earlier in
+	/// the same merge. The first occurrence of a handle wins and keeps its place, so the order
+	/// PAM tries the remaining keys in is unchanged. Only the handle is compared: a key with the
+	/// same handle but a different public key, algorithm or flags is still skipped.
+	SkipDuplicates,
+	/// Append every key, even if the user already has a key with the same handle
+	Append,
 }
 
 impl FromStr for MappingFile {
-	type Err = Error;
+	type Err = ParseError;
 
 	fn from_str(s: &str) -> Result<Self, Self::Err> {
-		let mappings = s
-			.lines()
-			.map(Mapping::from_str)
-			.collect::<Result<Vec<Mapping>, Error>>()?;
-		Ok(MappingFile { mappings })
+		MappingFile::parse(s, false)
+	}
+}
+
+/// Parses a mapping file from raw bytes, e.g. a buffer read from a file descriptor. The bytes
+/// must be valid UTF-8, and are parsed in place without copying them.
+impl TryFrom<&[u8]> for MappingFile {
+	type Error = FromBytesError;
+
+	fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+		Ok(core::str::from_utf8(bytes)?.parse()?)
+	}
+}
+
+/// Collects mappings into a file, in iteration order.
+///
+/// ```
+/// use pam_u2f_mapping::{Mapping, MappingFile};
+///
+/// let mappings = vec![
+///     "alice:h,p,es256,+presence".parse::<Mapping>().unwrap(),
+///     "bob:h,p,es256,+presence".parse::<Mapping>().unwrap(),
+/// ];
+/// let file = mappings.into_iter().collect::<MappingFile>();
+/// assert_eq!(file.len(), 2);
+/// ```
+impl FromIterator<Mapping> for MappingFile {
+	fn from_iter<T: IntoIterator<Item = Mapping>>(iter: T) -> Self {
+		MappingFile {
+			mappings: iter.into_iter().collect(),
+			trailing_comments: Vec::new(),
+		}
+	}
+}
+
+/// Appends mappings to the end of the file. Unlike [`MappingFile::merge`], no attempt is made to
+/// combine mappings for the same user.
+impl Extend<Mapping> for MappingFile {
+	fn extend<T: IntoIterator<Item = Mapping>>(&mut self, iter: T) {
+		self.mappings.extend(iter);
+	}
+}
+
+/// Wraps a single mapping in a file, keeping its comments
+impl From<Mapping> for MappingFile {
+	fn from(mapping: Mapping) -> Self {
+		MappingFile::from(alloc::vec![mapping])
+	}
+}
+
+/// Wraps mappings in a file without copying them. The mappings are kept in order and with their
+/// comments, so the file formats them the same way they'd format on their own.
+impl From<Vec<Mapping>> for MappingFile {
+	fn from(mappings: Vec<Mapping>) -> Self {
+		MappingFile {
+			mappings,
+			trailing_comments: Vec::new(),
+		}
+	}
+}
+
+/// Compares two strings by their lowercase mapping
+fn eq_ignore_case(a: &str, b: &str) -> bool {
+	a.chars()
+		.flat_map(char::to_lowercase)
+		.eq(b.chars().flat_map(char::to_lowercase))
+}
+
+/// Removes leading and trailing whitespace from a string without reallocating
+fn trim_in_place(s: &mut String) {
+	s.truncate(s.trim_end().len());
+	let start = s.len() - s.trim_start().len();
+	s.drain(..start);
+}
+
+/// A line of a mapping file that isn't blank
+enum Line<'a> {
+	/// A comment, as written including the `#`
+	Comment(&'a str),
+	/// A line holding a mapping, with its 1-based line number
+	Entry(usize, &'a str),
+}
+
+/// The lines of a mapping file that aren't blank. Carriage returns left over from Windows line
+/// endings are removed.
+fn lines(s: &str) -> impl Iterator<Item = Line<'_>> {
+	s.lines().enumerate().filter_map(|(index, line)| {
+		let line = trim_cr(line);
+		if !is_skipped(line) {
+			Some(Line::Entry(index + 1, line))
+		} else if is_comment(line) {
+			Some(Line::Comment(line))
+		} else {
+			None
+		}
+	})
+}
+
+/// The lines of a mapping file that hold a mapping, with their 1-based line numbers
+fn entry_lines(s: &str) -> impl Iterator<Item = (usize, &str)> {
+	lines(s).filter_map(|line| match line {
+		Line::Entry(number, text) => Some((number, text)),
+		Line::Comment(_) => None,
+	})
+}
+
+/// Whether a field contains a separator or line break, so it can't be written to a mapping line
+/// without changing how the line parses
+fn is_unwritable(field: &str) -> bool {
+	field.contains([',', ':', '\n', '\r'])
+}
+
+/// Removes a carriage return left at the end of a line
+fn trim_cr(line: &str) -> &str {
+	line.strip_suffix('\r').unwrap_or(line)
+}
+
+/// Whether a line in a mapping file holds no mapping, i.e. is blank or a comment
+fn is_skipped(line: &str) -> bool {
+	let line = line.trim_start();
+	line.is_empty() || line.starts_with('#')
+}
+
+/// Whether a line in a mapping file is a comment
+fn is_comment(line: &str) -> bool {
+	line.trim_start().starts_with('#')
+}
+
+/// Writes every mapping on its own line, preceded by its comments. Each line, including the last
+/// one, is terminated by a newline, so a file with no mappings or comments formats as an empty
+/// string.
+///
+/// Comments are written as they are stored. If one doesn't start with `#`, e.g. because it was
+/// added in code, `# ` is written before it so it can't be mistaken for a mapping. Comments
+/// containing line breaks are written as several comment lines.
+impl core::fmt::Display for MappingFile {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		for mapping in &self.mappings {
+			write_comments(f, &mapping.comments)?;
+			writeln!(f, "{mapping}")?;
+		}
+		write_comments(f, &self.trailing_comments)
 	}
 }
 
-impl std::str::FromStr for Mapping {
+/// Writes comment lines, making sure each one is read back as a comment
+fn write_comments(f: &mut core::fmt::Formatter<'_>, comments: &[String]) -> core::fmt::Result {
+	for line in comments.iter().flat_map(|comment| comment.split('\n')) {
+		let line = trim_cr(line);
+		if is_comment(line) {
+			writeln!(f, "{line}")?;
+		} else {
+			writeln!(f, "# {line}")?;
+		}
+	}
+	Ok(())
+}
+
+impl Mapping {
+	/// Whether the user has no keys
+	pub fn is_empty(&self) -> bool {
+		self.keys.is_empty()
+	}
+
+	/// The number of keys mapped to the user
+	pub fn key_count(&self) -> usize {
+		self.keys.len()
+	}
+
+	/// Replaces all of the user's keys
+	pub fn set_keys(&mut self, keys: impl IntoIterator<Item = Key>) {
+		self.keys.clear();
+		self.keys.extend(keys);
+	}
+
+	/// Adds a key to the end of the user's key list
+	pub fn add_key(&mut self, key: Key) {
+		self.keys.push(key);
+	}
+
+	/// Removes the first key with the given handle and returns it
+	pub fn remove_key(&mut self, handle: &str) -> Option<Key> {
+		let index = self.keys.iter().position(|key| key.handle == handle)?;
+		Some(self.keys.remove(index))
+	}
+
+	/// Finds the first key with the given handle
+	pub fn find_key(&self, handle: &str) -> Option<&Key> {
+		self.keys.iter().find(|key| key.handle == handle)
+	}
+
+	/// Checks that the mapping formats to a line that parses back to the same mapping, so a file
+	/// holding it can be loaded again after being saved. Fails with [`Error::InvalidUser`] if the
+	/// username contains `:` or a line break or starts with `#`, or has no keys and would be
+	/// rejected by [`Mapping::check_user`]. Each key is checked with [`Key::check`], and
+	/// [`Error::BadFlags`] is returned if the last flag on the line ends in whitespace, as
+	/// parsing would trim it.
+	pub fn check(&self) -> Result<(), Error> {
+		let Some(last) = self.keys.last() else {
+			return Mapping::check_user(&self.user);
+		};
+		if is_unwritable(&self.user) || self.user.trim_start().starts_with('#') {
+			return Err(Error::InvalidUser);
+		}
+		for key in &self.keys {
+			key.check()?;
+		}
+		if last
+			.flags
+			.names()
+			.last()
+			.is_some_and(|flag| flag.ends_with(char::is_whitespace))
+		{
+			return Err(Error::BadFlags);
+		}
+		Ok(())
+	}
+
+	/// Checks that a username formats to a line that parses back to the same user. Fails with
+	/// [`Error::InvalidUser`] if it contains `:` or a line break, is blank, starts with `#`, or
+	/// ends in whitespace, as the formatted line would then map different users or none at all.
+	///
+	/// This is less strict than [`UsernameProblem::check`], which warns about usernames that
+	/// can be written but are unlikely to match an account.
+	pub fn check_user(user: &str) -> Result<(), Error> {
+		let start = user.trim_start();
+		if user.contains([':', '\n', '\r'])
+			|| start.is_empty()
+			|| start.starts_with('#')
+			|| user.trim_end().len() != user.len()
+		{
+			return Err(Error::InvalidUser);
+		}
+		Ok(())
+	}
+
+	/// Parses the keys from a line without a username, as printed by `pamu2fcfg -n`. Such lines
+	/// start with a colon and are meant to be appended to an existing user's mapping.
+	///
+	/// A line break at the end is ignored, but one anywhere else fails with
+	/// [`Error::LineBreak`], as each line should be parsed separately.
+	pub fn parse_keys_only(s: &str) -> Result<Vec<Key>, Error> {
+		let s = s.trim_end_matches(['\r', '\n']);
+		if s.contains(['\r', '\n']) {
+			return Err(Error::LineBreak);
+		}
+		if !s.starts_with(':') {
+			return Err(Error::UnexpectedUser);
+		}
+		Ok(s.parse::<Mapping>()?.keys)
+	}
+
+	/// Parses a single mapping line. On failure the byte range within the line that caused the
+	/// error is returned along with it. In strict mode, the username must not be empty, key data
+	/// is checked to be valid base64 and keys may not have more than four fields.
+	fn parse_spanned(s: &str, strict: bool) -> Result<Mapping, (Error, Range<usize>)> {
+		MappingRef::parse_spanned(s, strict).map(|mapping| mapping.to_owned())
+	}
+}
+
+impl core::str::FromStr for Mapping {
 	type Err = Error;
 
 	fn from_str(s: &str) -> Result<Self, Self::Err> {
-		let mut fields = s.split(':');
-		let user = fields.next().ok_or(Error::UserMissing)?;
-		let mut keys = Vec::new();
-		for field in fields {
-			let mut subfields = field.split(',');
-			// split will always yield at least one item
-			let public = subfields.next().unwrap().to_owned();
-			let handle = subfields.next().ok_or(Error::HandleMissing)?.to_owned();
-			let kind = subfields.next().ok_or(Error::KindMissing)?.to_owned();
-			let flags = subfields.next().ok_or(Error::FlagsMissing)?.to_owned();
-			let mut flags = flags.split('+');
-			if flags.next() != Some("") {
-				return Err(Error::BadFlags);
-			}
-			let flags = flags.map(|s| s.to_owned()).collect::<Vec<_>>();
-			keys.push(Key {
-				public,
-				handle,
-				kind,
-				flags,
-			})
-		}
-		Ok(Mapping {
-			user: user.to_owned(),
-			keys,
-		})
+		Mapping::parse_spanned(s, false).map_err(|(error, _)| error)
 	}
 }
 
-impl std::fmt::Display for Mapping {
-	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Display for Mapping {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
 		write!(f, "{}", self.user)?;
 		for key in &self.keys {
-			write!(f, ":{},{},{},", &key.handle, &key.public, &key.kind)?;
-			for flag in &key.flags {
-				write!(f, "+{flag}")?;
-			}
+			write!(
+				f,
+				":{},{},{},{}",
+				&key.handle, &key.public, &key.kind, &key.flags
+			)?;
 		}
 		Ok(())
 	}
 }
 
 /// The key contained invalid data and failed to parse
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Error {
 	/// User field was missing from a mapping
 	UserMissing,
-	/// Key handle was missing
-	HandleMissing,
-	/// Key kind was missing
-	KindMissing,
-	/// Key flags were missing
-	FlagsMissing,
+	/// The username was empty, e.g. on a line starting with a colon. Only reported when parsing
+	/// strictly.
+	EmptyUser,
+	/// Second half of the key data, the public key, was missing, so the key only had one of its
+	/// four fields
+	HandleMissing {
+		/// The position of the key on the line, starting at 0
+		key_index: usize,
+	},
+	/// Key kind was missing, so the key only had two of its four fields
+	KindMissing {
+		/// The position of the key on the line, starting at 0
+		key_index: usize,
+	},
+	/// Key flags were missing, so the key only had three of its four fields
+	FlagsMissing {
+		/// The position of the key on the line, starting at 0
+		key_index: usize,
+	},
 	/// Key flags were malformed
 	BadFlags,
+	/// A key had more fields after its flags, which usually means the line is corrupted or in a
+	/// different format. Only reported when parsing strictly, otherwise the extra fields are
+	/// ignored.
+	ExtraFields,
+	/// A username was found where only keys were expected
+	UnexpectedUser,
+	/// A username can't be written to a mapping file, see [`Mapping::check_user`]
+	InvalidUser,
+	/// A line break was found in input that should be a single line
+	LineBreak,
+	/// A key field contains a separator or line break, so it can't be written to a mapping
+	/// file, see [`Key::check`]
+	BadField {
+		/// The field that can't be written
+		field: Field,
+	},
+	/// An unknown algorithm can't be written to a mapping file, see [`Key::check`]
+	BadKind,
+	/// Key data wasn't valid base64
+	InvalidBase64 {
+		/// The field that failed to decode
+		field: Field,
+	},
 }
 
-impl std::fmt::Display for Error {
-	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+/// A key data field of a [`Key`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+	/// The key handle
+	Handle,
+	/// The public key
+	Public,
+}
+
+impl Error {
+	/// The position on the line of the key the error is about, if it's about a truncated key
+	pub fn key_index(&self) -> Option<usize> {
+		match *self {
+			Error::HandleMissing { key_index }
+			| Error::KindMissing { key_index }
+			| Error::FlagsMissing { key_index } => Some(key_index),
+			_ => None,
+		}
+	}
+
+	/// Shifts the key index of a truncated key error, for keys parsed apart from the keys
+	/// before them on the line
+	pub(crate) fn offset_key_index(self, offset: usize) -> Error {
+		match self {
+			Error::HandleMissing { key_index } => Error::HandleMissing {
+				key_index: key_index + offset,
+			},
+			Error::KindMissing { key_index } => Error::KindMissing {
+				key_index: key_index + offset,
+			},
+			Error::FlagsMissing { key_index } => Error::FlagsMissing {
+				key_index: key_index + offset,
+			},
+			other => other,
+		}
+	}
+}
+
+impl core::fmt::Display for Error {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
 		use Error::*;
 		let s = match *self {
 			UserMissing => "Entry has no username",
-			HandleMissing => "Missing second half of key data",
-			KindMissing => "Entry has no key type",
-			FlagsMissing => "Entry has no flags",
+			EmptyUser => "Entry has an empty username",
+			HandleMissing { key_index } => {
+				let key = key_index + 1;
+				return write!(
+					f,
+					"Key {key} has only 1 of 4 fields, the public key is missing"
+				);
+			}
+			KindMissing { key_index } => {
+				let key = key_index + 1;
+				return write!(
+					f,
+					"Key {key} has only 2 of 4 fields, the key type is missing"
+				);
+			}
+			FlagsMissing { key_index } => {
+				let key = key_index + 1;
+				return write!(f, "Key {key} has only 3 of 4 fields, the flags are missing");
+			}
 			BadFlags => "Entry has ill-formed flags",
+			ExtraFields => "Entry has unexpected fields after the flags",
+			UnexpectedUser => "Entry has a username, expected only keys",
+			InvalidUser => "Username can't be written to a mapping file",
+			LineBreak => "Entry spans more than one line",
+			BadField {
+				field: Field::Handle,
+			} => "Key handle contains a separator or line break",
+			BadField {
+				field: Field::Public,
+			} => "Public key contains a separator or line break",
+			BadKind => "Key algorithm can't be written to a mapping file",
+			InvalidBase64 {
+				field: Field::Handle,
+			} => "Key handle is not valid base64",
+			InvalidBase64 {
+				field: Field::Public,
+			} => "Public key is not valid base64",
 		};
 		f.write_str(s)
 	}
 }
 
+#[cfg(any(feature = "std", test))]
 impl std::error::Error for Error {}
 
+/// An [`Error`] together with the location in a mapping file where it occurred
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+	/// The line the error occurred on, starting at 1
+	pub line: usize,
+	/// The byte range within the line that failed to parse
+	pub span: Range<usize>,
+	/// What went wrong
+	pub error: Error,
+	/// The contents of the line that failed to parse
+	pub text: String,
+}
+
+impl ParseError {
+	/// How many characters of the line are shown when displaying the error
+	const PREVIEW_LEN: usize = 40;
+
+	pub(crate) fn new(line: usize, text: &str, (error, span): (Error, Range<usize>)) -> Self {
+		ParseError {
+			line,
+			span,
+			error,
+			text: text.to_owned(),
+		}
+	}
+}
+
+/// Long lines are truncated, as key data easily runs to hundreds of characters.
+impl core::fmt::Display for ParseError {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		write!(f, "line {}: could not parse \"", self.line)?;
+		match self.text.char_indices().nth(Self::PREVIEW_LEN) {
+			Some((end, _)) => write!(f, "{}…", &self.text[..end])?,
+			None => f.write_str(&self.text)?,
+		}
+		write!(f, "\": {}", self.error)
+	}
+}
+
+#[cfg(any(feature = "std", test))]
+impl std::error::Error for ParseError {}
+
+/// An error parsing a mapping file from bytes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FromBytesError {
+	/// The bytes weren't valid UTF-8
+	Utf8(Utf8Error),
+	/// The file contents were malformed
+	Parse(ParseError),
+}
+
+impl From<Utf8Error> for FromBytesError {
+	fn from(err: Utf8Error) -> Self {
+		FromBytesError::Utf8(err)
+	}
+}
+
+impl From<ParseError> for FromBytesError {
+	fn from(err: ParseError) -> Self {
+		FromBytesError::Parse(err)
+	}
+}
+
+impl core::fmt::Display for FromBytesError {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		match self {
+			FromBytesError::Utf8(err) => write!(f, "Mapping file is not valid UTF-8: {err}"),
+			FromBytesError::Parse(err) => err.fmt(f),
+		}
+	}
+}
+
+#[cfg(any(feature = "std", test))]
+impl std::error::Error for FromBytesError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			FromBytesError::Utf8(err) => Some(err),
+			FromBytesError::Parse(_) => None,
+		}
+	}
+}
+
 #[cfg(test)]
 mod tests {
-	use super::Mapping;
+	use super::{
+		Algorithm, EmptyUsers, Error, Field, FromBytesError, Key, Mapping, MappingFile,
+		MergeStrategy, ParseError,
+	};
 	type BoxError = Box<dyn std::error::Error>;
 	const TEST_MAPPING: &str = "alice:\
 		owBYtYMabYlexEG10ildyDLNqwkpeIZyc4YwqP6yUnqlQ3DCxNMjPXoGcQOPiNXu2kFuGKs\
@@ -161,8 +1122,8 @@ mod tests {
 			"IiFyv2O8qSG517c2ghvHEbMb6xs5ToPaoOXdgGkkorH2ta/iYWtOhMB7wxaiS3BhOHSxcJUJJkMLmfUWl8Uivw==",
 			"public key mismatch"
 		);
-		assert_eq!(key.kind, "es256");
-		assert_eq!(key.flags, &["presence"]);
+		assert_eq!(key.kind.as_str(), "es256");
+		assert!(key.flags().presence && !key.flags().pin);
 		Ok(())
 	}
 	/// Asserts that a file gets parsed and formatted to the same data
@@ -171,4 +1132,554 @@ mod tests {
 		assert_eq!(TEST_MAPPING.parse::<Mapping>()?.to_string(), TEST_MAPPING);
 		Ok(())
 	}
+	/// Asserts that a file with several users, algorithms and flag combinations is reproduced
+	/// byte for byte
+	#[test]
+	fn file_byte_for_byte() -> Result<(), BoxError> {
+		let file = format!(
+			"{TEST_MAPPING}:\
+			pU3KGCUwux1tEyze1iN7LtkeP3IfyxlxF0SU1kk8nVw0YL4xIB5p/tqg7ui5mX9c,\
+			fCmZ/a/lkyU81lSvTfrXFCegrrP+6SMvivIhH57kkcWxC+y1Vjv8Hm+TQn7LyP4pVeXNjkbcjtS3wnZNKlpNdg==,\
+			es256,+presence+pin\n\
+			bob:\
+			dwb4XYaQAkrWvaNAG+nIy8zJNfbNH2EiauFTOK4aNABNM7oNJGrATIGxuvI+O/nu,\
+			9fefK0k0r4f1UgtpuUsNmC6Fu1W2cqhyY3rNdGb8tg4Oj/GEY7DksropcDR08GSsaPcA9bArPcZm9FveqizK7Q==,\
+			rs256,\n\
+			carol:\
+			zStRV0EOTe5K8rNPQwoHNEfeY2wOgGyVe6aE1kMfteo=,\
+			10JNCeFdAkxYSPI9H6b3Nh1/YY0VMucOIOKmZo3n9H4=,\
+			eddsa,+presence+verification+resident\n\
+			dave:\
+			zStRV0EOTe5K8rNPQwoHNEfeY2wOgGyVe6aE1kMfteo=,\
+			10JNCeFdAkxYSPI9H6b3Nh1/YY0VMucOIOKmZo3n9H4=,\
+			eddsa,+verification+pin+presence+pin\n"
+		);
+		assert_eq!(MappingFile::parse_strict(&file)?.to_string(), file);
+		Ok(())
+	}
+	/// Asserts that a whole file round-trips, and that an empty file stays empty. The handle and
+	/// public key are the same, as only the lines are under test here, see `non_destructive`.
+	#[test]
+	fn file_non_destructive() -> Result<(), BoxError> {
+		let file = "alice:a2V5,a2V5,es256,+presence\nbob:a2V5,a2V5,es256,+presence\n";
+		assert_eq!(file.parse::<MappingFile>()?.to_string(), file);
+		assert_eq!("".parse::<MappingFile>()?.to_string(), "");
+		Ok(())
+	}
+	/// Asserts that errors point at the line and field that broke
+	#[test]
+	fn error_location() {
+		let file = format!("{TEST_MAPPING}\nbob:handle,public,es256,+presence:handle,public\n");
+		let err = file.parse::<MappingFile>().unwrap_err();
+		assert_eq!(
+			err,
+			ParseError {
+				line: 2,
+				span: 34..47,
+				error: Error::KindMissing { key_index: 1 },
+				text: file.lines().nth(1).unwrap().into(),
+			}
+		);
+		assert_eq!(
+			err.to_string(),
+			"line 2: could not parse \"bob:handle,public,es256,+presence:handle…\": \
+			Key 2 has only 2 of 4 fields, the key type is missing"
+		);
+		let err = "alice::::".parse::<MappingFile>().unwrap_err();
+		assert_eq!(
+			err.to_string(),
+			"line 1: could not parse \"alice::::\": Key 1 has only 1 of 4 fields, the public key \
+			is missing"
+		);
+		assert_eq!(err.error.key_index(), Some(0));
+	}
+	/// Asserts that blank and comment lines are skipped without throwing off line numbers
+	#[test]
+	fn skip_comments() -> Result<(), BoxError> {
+		let file = format!("# alice's laptop\n{TEST_MAPPING}\n  \n\t# nothing here\n\nbob:x\n");
+		let err = file.parse::<MappingFile>().unwrap_err();
+		assert_eq!(err.line, 6);
+		let file = format!("\n# alice's laptop\n{TEST_MAPPING}\n\n");
+		let parsed = file.parse::<MappingFile>()?;
+		assert_eq!(parsed.mappings.len(), 1);
+		assert_eq!(
+			parsed.to_string(),
+			format!("# alice's laptop\n{TEST_MAPPING}\n")
+		);
+		Ok(())
+	}
+	/// Asserts that comments stay with the mapping below them
+	#[test]
+	fn comments() -> Result<(), BoxError> {
+		let text = "# header\n\n# issued 2023, rotate Q4\nbob:b,p,es256,\n  # indented\n\
+			alice:a,p,es256,\n# the end\n";
+		let mut file: MappingFile = text.parse()?;
+		assert_eq!(
+			file.mappings[0].comments,
+			["# header", "# issued 2023, rotate Q4"]
+		);
+		assert_eq!(file.trailing_comments, ["# the end"]);
+		assert_eq!(file.to_string(), text.replace("\n\n", "\n"));
+		file.normalize();
+		assert_eq!(
+			file.to_string(),
+			"  # indented\nalice:a,p,es256,\n# header\n# issued 2023, rotate Q4\n\
+			bob:b,p,es256,\n# the end\n"
+		);
+		file.remove_user("alice");
+		file.mappings[0].comments = vec!["no hash\nsecond".into()];
+		assert_eq!(
+			file.to_string(),
+			"# no hash\n# second\nbob:b,p,es256,\n# the end\n"
+		);
+		let (file, _) = MappingFile::parse_collecting("# broken\nalice:x\n# kept\nbob");
+		assert_eq!(file.mappings[0].comments, ["# kept"]);
+		Ok(())
+	}
+	/// Asserts that strict parsing rejects corrupted key data that lenient parsing accepts
+	#[test]
+	fn strict() -> Result<(), BoxError> {
+		MappingFile::parse_strict(TEST_MAPPING)?;
+		let truncated = TEST_MAPPING.replacen("Uivw==", "Uiv", 1);
+		truncated.parse::<MappingFile>()?;
+		let err = MappingFile::parse_strict(&truncated).unwrap_err();
+		assert_eq!(
+			err.error,
+			Error::InvalidBase64 {
+				field: Field::Public
+			}
+		);
+		assert!(truncated[err.span].starts_with("IiFy"));
+		let url_safe = TEST_MAPPING.replace('/', "_").replace('+', "-");
+		MappingFile::parse_strict(&url_safe.replace("-presence", "+presence"))?;
+		":h,p,es256,".parse::<MappingFile>()?;
+		let extra = "alice:aGFuZGxl,cHVibGlj,es256,+presence,garbage,more:a,b,c,";
+		assert_eq!(extra.parse::<MappingFile>()?.mappings[0].keys.len(), 2);
+		let err = MappingFile::parse_strict(extra).unwrap_err();
+		assert_eq!(err.error, Error::ExtraFields);
+		assert_eq!(&extra[err.span], "garbage,more");
+		let err = "alice:h,p,es256,presence,x"
+			.parse::<MappingFile>()
+			.unwrap_err();
+		assert_eq!(&err.text[err.span], "presence");
+		for line in [":", ":h,p,es256,"] {
+			let err = MappingFile::parse_strict(line).unwrap_err();
+			assert_eq!((err.error, err.span), (Error::EmptyUser, 0..0));
+		}
+		Ok(())
+	}
+	#[test]
+	fn decode_key() -> Result<(), BoxError> {
+		let mut mapping: Mapping = TEST_MAPPING.parse()?;
+		let key = &mut mapping.keys[0];
+		assert_eq!(key.public_bytes()?.len(), 64);
+		assert_eq!(key.handle_bytes()?[..2], [0xa3, 0x00]);
+		let copy = Key::from_credential(
+			&key.handle_bytes()?,
+			&key.public_bytes()?,
+			key.kind.clone(),
+			key.flags.clone(),
+		);
+		assert_eq!(&copy, key);
+		key.handle.push('!');
+		assert_eq!(
+			key.handle_bytes(),
+			Err(Error::InvalidBase64 {
+				field: Field::Handle
+			})
+		);
+		Ok(())
+	}
+	/// Asserts that equality ignores the written order of known flags
+	#[test]
+	fn equality() -> Result<(), BoxError> {
+		let a: MappingFile = "alice:h,p,es256,+pin+presence".parse()?;
+		let b: MappingFile = "alice:h,p,es256,+presence+pin".parse()?;
+		assert_eq!(a, b);
+		let c: MappingFile = "alice:h,p,eddsa,+presence+pin".parse()?;
+		assert_ne!(a, c);
+		Ok(())
+	}
+	#[test]
+	fn lookup() -> Result<(), BoxError> {
+		let mut file: MappingFile = "alice:h,p,es256,+presence\nbob:h,p,es256,\nalice\n".parse()?;
+		assert_eq!(file.get("alice").map(|m| m.keys.len()), Some(1));
+		assert!(file.contains_user("bob"));
+		assert!(!file.contains_user("carol"));
+		assert!(!file.contains_user("Alice"));
+		assert_eq!(file.get_ci("ALICE").map(|m| m.keys.len()), Some(1));
+		assert!(file.contains_user_ci("Bob"));
+		assert!(!file.contains_user_ci("Carol"));
+		file.get_mut("bob").unwrap().keys.clear();
+		assert!(file.mappings[1].keys.is_empty());
+		Ok(())
+	}
+	#[test]
+	fn key_counts() -> Result<(), BoxError> {
+		let file: MappingFile = "alice:a,p,es256,:b,p,es256,
+bob
+carol:c,p,es256,
+dave"
+			.parse()?;
+		assert_eq!(file.mappings[0].key_count(), 2);
+		assert_eq!(file.total_keys(), 3);
+		assert_eq!(
+			file.users_without_keys().collect::<Vec<_>>(),
+			["bob", "dave"]
+		);
+		assert_eq!(MappingFile::default().total_keys(), 0);
+		Ok(())
+	}
+	#[test]
+	fn edit_users() -> Result<(), BoxError> {
+		let mut file = MappingFile::default();
+		assert!(file.is_empty());
+		file = "alice:h,p,es256,+presence\nbob".parse()?;
+		assert_eq!(file.len(), 2);
+		assert!(!file.add_user("alice")?.is_empty());
+		assert!(file.add_user("bob")?.is_empty());
+		file.add_user("carol")?;
+		assert_eq!(file.mappings.len(), 3);
+		assert!(!file.rename_user("carol", "bob"));
+		assert!(!file.rename_user("dave", "erin"));
+		assert!(file.rename_user("carol", "dave"));
+		assert_eq!(file.remove_user("bob").map(|m| m.user), Some("bob".into()));
+		assert_eq!(file.remove_user("bob"), None);
+		assert_eq!(file.to_string(), "alice:h,p,es256,+presence\ndave\n");
+		let keys = Mapping::parse_keys_only(":a,p,es256,:b,p,es256,")?;
+		assert!(file.set_user_keys("alice", keys.clone())?);
+		assert!(!file.set_user_keys("erin", keys.into_iter().rev())?);
+		assert_eq!(
+			file.to_string(),
+			"alice:a,p,es256,:b,p,es256,\ndave\nerin:b,p,es256,:a,p,es256,\n"
+		);
+		file.mappings[0].set_keys(None);
+		assert!(file.mappings[0].is_empty());
+		Ok(())
+	}
+	/// Asserts that usernames that would format to a different mapping are rejected
+	#[test]
+	fn invalid_users() -> Result<(), BoxError> {
+		let mut file: MappingFile = "alice".parse()?;
+		for user in [
+			"bob:evil,p,es256,\nroot",
+			"bob\r",
+			"",
+			" ",
+			"#bob",
+			" #bob",
+			"bob ",
+		] {
+			assert_eq!(
+				file.add_user(user).err(),
+				Some(Error::InvalidUser),
+				"{user:?}"
+			);
+			assert!(!file.rename_user("alice", user), "{user:?}");
+		}
+		assert_eq!(file.to_string(), "alice\n");
+		assert_eq!(Mapping::check_user(" bob#"), Ok(()));
+		// Parsed mappings always pass, even those check_user would reject
+		for line in [
+			":h,p,es256,",
+			"bob :h,p,fancy,+x",
+			"alice:h,p,es256,+presence +pin",
+		] {
+			assert_eq!(line.parse::<Mapping>()?.check(), Ok(()), "{line:?}");
+		}
+		let mut mapping: Mapping = "alice:h,p,es256,+x".parse()?;
+		mapping.keys[0].flags.other[0].push(' ');
+		assert_eq!(mapping.check(), Err(Error::BadFlags));
+		mapping.keys[0].flags.other.clear();
+		mapping.keys[0].handle.push_str(",q");
+		let field = Field::Handle;
+		assert_eq!(mapping.check(), Err(Error::BadField { field }));
+		mapping.keys[0].handle = "h".into();
+		mapping.keys[0].kind = Algorithm::Other("x:y".into());
+		assert_eq!(mapping.check(), Err(Error::BadKind));
+		mapping.keys[0].kind = Algorithm::Other("es256".into());
+		assert_eq!(mapping.check(), Err(Error::BadKind));
+		Ok(())
+	}
+	#[test]
+	fn edit_keys() -> Result<(), BoxError> {
+		let mut mapping: Mapping = "alice:a,p,es256,+presence:b,p,es256,".parse()?;
+		let key = mapping.remove_key("a").unwrap();
+		assert!(mapping.find_key("a").is_none());
+		assert!(mapping.remove_key("a").is_none());
+		mapping.add_key(key);
+		assert_eq!(mapping.to_string(), "alice:b,p,es256,:a,p,es256,+presence");
+		assert_eq!(
+			mapping.find_key("a").map(|key| key.flags.presence),
+			Some(true)
+		);
+		Ok(())
+	}
+	#[test]
+	fn merge() -> Result<(), BoxError> {
+		let base: MappingFile = "alice:a,p,es256,\nbob:b,p,es256,".parse()?;
+		let other: MappingFile = "carol:a,p,es256,\nalice:a,p,es256,:c,p,es256,".parse()?;
+		let mut skipped = base.clone();
+		skipped.merge(other.clone(), MergeStrategy::SkipDuplicates);
+		assert_eq!(
+			skipped.to_string(),
+			"alice:a,p,es256,:c,p,es256,\nbob:b,p,es256,\ncarol:a,p,es256,\n"
+		);
+		let mut appended = base;
+		appended.merge(other, MergeStrategy::Append);
+		assert_eq!(appended.get("alice").unwrap().keys.len(), 3);
+		Ok(())
+	}
+	#[test]
+	fn summary() -> Result<(), BoxError> {
+		let mapping: Mapping = "alice:owBYwWdlc2tleQ==,p,es256,+presence+pin:h,p,eddsa,".parse()?;
+		assert_eq!(mapping.keys[0].summary(), "es256 owBYwWdl… +presence+pin");
+		assert_eq!(mapping.keys[1].summary(), "eddsa h");
+		assert_eq!(
+			mapping.to_string(),
+			"alice:owBYwWdlc2tleQ==,p,es256,+presence+pin:h,p,eddsa,"
+		);
+		Ok(())
+	}
+	#[cfg(feature = "std")]
+	#[test]
+	fn index() -> Result<(), BoxError> {
+		let file: MappingFile = "alice:a,p,es256,\nbob\nalice:b,p,es256,\n".parse()?;
+		let index = file.index();
+		assert_eq!(index.len(), 2);
+		assert_eq!(index["alice"].keys[0].handle, "a");
+		for user in ["alice", "bob", "carol"] {
+			assert_eq!(index.get(user).copied(), file.get(user));
+		}
+		Ok(())
+	}
+	#[test]
+	fn from_mappings() -> Result<(), BoxError> {
+		let alice: Mapping = "alice:a,p,es256,".parse()?;
+		let bob: Mapping = "bob".parse()?;
+		assert_eq!(
+			MappingFile::from(alice.clone()).to_string(),
+			"alice:a,p,es256,\n"
+		);
+		let file = MappingFile::from(vec![alice.clone(), bob.clone()]);
+		assert_eq!(file, [alice, bob].into_iter().collect());
+		assert!(MappingFile::from(Vec::new()).is_empty());
+		Ok(())
+	}
+	/// Asserts that deduplicating keeps the first occurrence of each handle in its place
+	#[test]
+	fn merge_stable() -> Result<(), BoxError> {
+		let mut file: MappingFile = "alice:b,p,es256,:d,p,es256,".parse()?;
+		let other: MappingFile =
+			"alice:a,p,es256,:d,q,eddsa,+pin:c,p,es256,:a,q,es256,:b,p,es256,:e,p,es256,"
+				.parse()?;
+		file.merge(other, MergeStrategy::SkipDuplicates);
+		let alice = file.get("alice").unwrap();
+		let handles = alice.keys.iter().map(|key| key.handle.as_str());
+		assert_eq!(handles.collect::<Vec<_>>(), ["b", "d", "a", "c", "e"]);
+		assert_eq!(alice.find_key("d").unwrap().public, "p");
+		assert_eq!(alice.find_key("a").unwrap().public, "p");
+		Ok(())
+	}
+	#[test]
+	fn keys_only() -> Result<(), BoxError> {
+		let keys = Mapping::parse_keys_only(":a,p,es256,+presence:b,p,eddsa,\n")?;
+		assert_eq!(keys.len(), 2);
+		assert_eq!(keys[1].handle, "b");
+		let mut mapping: Mapping = "alice:c,p,es256,".parse()?;
+		mapping.keys.extend(keys);
+		assert_eq!(
+			mapping.to_string(),
+			"alice:c,p,es256,:a,p,es256,+presence:b,p,eddsa,"
+		);
+		assert_eq!(
+			Mapping::parse_keys_only("alice:a,p,es256,"),
+			Err(Error::UnexpectedUser)
+		);
+		for s in [
+			":a,p,es256,+presence\n:b,p,es256,+pin",
+			":a,p,es256,\r:b,p,es256,",
+		] {
+			assert_eq!(Mapping::parse_keys_only(s), Err(Error::LineBreak), "{s:?}");
+		}
+		assert_eq!(Mapping::parse_keys_only(":a,p,es256,\r\n")?.len(), 1);
+		Ok(())
+	}
+	/// Asserts that Windows line endings don't end up in the last field of a line
+	#[test]
+	fn crlf() -> Result<(), BoxError> {
+		let file: MappingFile =
+			"alice:h,p,es256,+presence\r\nbob:h,p,es256,+presence+pin\r".parse()?;
+		assert!(file
+			.mappings
+			.iter()
+			.all(|m| m.keys[0].flags.other.is_empty()));
+		assert!(file.mappings[1].keys[0].flags.pin);
+		let file: MappingFile = "alice:h,p,es256,+presence+fancy\r\n".parse()?;
+		assert_eq!(file.mappings[0].keys[0].flags.other, &["fancy"]);
+		Ok(())
+	}
+	#[test]
+	fn collect() -> Result<(), BoxError> {
+		let mut file: MappingFile = ["alice", "bob"]
+			.into_iter()
+			.map(str::parse::<Mapping>)
+			.collect::<Result<_, _>>()?;
+		file.extend(Some("carol".parse()?));
+		assert_eq!(file.to_string(), "alice\nbob\ncarol\n");
+		Ok(())
+	}
+	#[test]
+	fn key_flags() -> Result<(), BoxError> {
+		let mut mapping: Mapping = "alice:h,p,es256,+presence+fancy".parse()?;
+		let key = &mut mapping.keys[0];
+		assert!(key.requires_presence() && !key.requires_pin() && !key.requires_verification());
+		key.set_pin(true);
+		key.set_presence(false);
+		key.set_verification(true);
+		assert!(key.requires_pin() && key.requires_verification());
+		assert_eq!(
+			mapping.to_string(),
+			"alice:h,p,es256,+fancy+pin+verification"
+		);
+		Ok(())
+	}
+	#[test]
+	fn from_bytes() -> Result<(), BoxError> {
+		let file = MappingFile::try_from(TEST_MAPPING.as_bytes())?;
+		assert_eq!(file.mappings[0].user, "alice");
+		let err = MappingFile::try_from(&b"alice:h\xff,p,es256,"[..]).unwrap_err();
+		assert!(matches!(err, FromBytesError::Utf8(e) if e.valid_up_to() == 7));
+		let err = MappingFile::try_from(&b"alice:h"[..]).unwrap_err();
+		assert!(
+			matches!(err, FromBytesError::Parse(e) if e.error == Error::HandleMissing { key_index: 0 })
+		);
+		Ok(())
+	}
+	#[test]
+	fn iterate_keys() -> Result<(), BoxError> {
+		let mut file: MappingFile = "alice:a,p,es256,:b,p,rs256,\nbob\ncarol:c,p,rs256,".parse()?;
+		let keys = file.keys().map(|(user, key)| (user, key.handle.as_str()));
+		assert_eq!(
+			keys.collect::<Vec<_>>(),
+			[("alice", "a"), ("alice", "b"), ("carol", "c")]
+		);
+		for (_, key) in file.keys_mut() {
+			key.flags.pin = true;
+		}
+		assert!(file.keys().all(|(_, key)| key.flags.pin));
+		let (user, key) = file.find_by_handle("c").unwrap();
+		assert_eq!((user, key.kind.as_str()), ("carol", "rs256"));
+		assert!(file.find_by_handle("d").is_none());
+		Ok(())
+	}
+	#[test]
+	fn collect_errors() {
+		let (file, errors) =
+			MappingFile::parse_collecting("alice:h\nbob\n# x\ncarol:h,p,es256\ndave:h,p,es256,");
+		assert_eq!(file.to_string(), "bob\ndave:h,p,es256,\n");
+		let errors = errors.iter().map(|e| (e.line, e.error)).collect::<Vec<_>>();
+		assert_eq!(
+			errors,
+			[
+				(1, Error::HandleMissing { key_index: 0 }),
+				(4, Error::FlagsMissing { key_index: 0 })
+			]
+		);
+	}
+	#[test]
+	fn entries() {
+		let entries = MappingFile::entries("alice:h,p,es256,\n# x\n\nbob:h\r\ncarol")
+			.map(|(number, text, result)| (number, text, result.map(|m| m.user)))
+			.collect::<Vec<_>>();
+		assert_eq!(
+			entries,
+			[
+				(1, "alice:h,p,es256,", Ok("alice".into())),
+				(4, "bob:h", Err(Error::HandleMissing { key_index: 0 })),
+				(5, "carol", Ok("carol".into())),
+			]
+		);
+	}
+	#[test]
+	fn split() -> Result<(), BoxError> {
+		let file: MappingFile = "# x\nalice:a,p,es256,\nbob\nalice:b,p,es256,\n".parse()?;
+		let parts = file.split().collect::<Vec<_>>();
+		assert_eq!(
+			parts,
+			[
+				("alice", "alice:a,p,es256,\n".into()),
+				("bob", "bob\n".into())
+			]
+		);
+		let mut merged = MappingFile::default();
+		for (_, part) in parts {
+			merged.merge(part.parse()?, MergeStrategy::SkipDuplicates);
+		}
+		assert_eq!(merged.to_string(), "alice:a,p,es256,\nbob\n");
+		Ok(())
+	}
+	#[test]
+	fn normalize() -> Result<(), BoxError> {
+		let mut file: MappingFile = "bob :b,p,es256,+x+pin+x+presence:b,p,es256,+presence+pin+x\n\
+			alice:a,p, eddsa ,+presence:c,p,es256,:a,p,eddsa,+presence\n\
+			alice:d,p,es256,"
+			.parse()?;
+		file.normalize();
+		assert_eq!(
+			file.to_string(),
+			"alice:a,p,eddsa,+presence:c,p,es256,\n\
+			alice:d,p,es256,\n\
+			bob :b,p,es256,+presence+pin+x\n"
+		);
+		// The untrimmed username never matched, so it mustn't shadow the entry PAM uses
+		let mut file: MappingFile = " alice:h,p,es256,\nalice:k,p,es256,".parse()?;
+		file.normalize();
+		assert_eq!(file.get("alice").unwrap().keys[0].handle, "k");
+		let mut file: MappingFile = "alice:h,p,es256,+x+verification+pin+pin\n".parse()?;
+		assert_eq!(
+			file.to_string(),
+			"alice:h,p,es256,+x+verification+pin+pin\n"
+		);
+		file.normalize();
+		assert_eq!(file.to_string(), "alice:h,p,es256,+pin+verification+x\n");
+		Ok(())
+	}
+	#[test]
+	fn collections() -> Result<(), BoxError> {
+		let file: MappingFile = "bob:a,p,es256,:a,p,es256,\nalice:b,p,es256,".parse()?;
+		let mut sorted = file.mappings.clone();
+		sorted.sort();
+		assert_eq!(sorted[0].user, "alice");
+		let by_user = file
+			.mappings
+			.iter()
+			.map(|mapping| (mapping.user.as_str(), mapping))
+			.collect::<std::collections::BTreeMap<_, _>>();
+		assert_eq!(by_user.keys().collect::<Vec<_>>(), [&"alice", &"bob"]);
+		let keys = file
+			.keys()
+			.map(|(_, key)| key)
+			.collect::<std::collections::HashSet<_>>();
+		assert_eq!(keys.len(), 2);
+		Ok(())
+	}
+	#[test]
+	fn retain_keys() -> Result<(), BoxError> {
+		let file: MappingFile =
+			"alice:lost,p,es256,:a,p,es256,\nbob:lost,p,es256,\ncarol".parse()?;
+		let mut kept = file.clone();
+		assert_eq!(
+			kept.retain_keys(EmptyUsers::Keep, |_, key| key.handle != "lost"),
+			2
+		);
+		assert_eq!(kept.to_string(), "alice:a,p,es256,\nbob\ncarol\n");
+		let mut removed = file;
+		assert_eq!(
+			removed.retain_keys(EmptyUsers::Remove, |_, key| key.handle != "lost"),
+			2
+		);
+		assert_eq!(removed.to_string(), "alice:a,p,es256,\ncarol\n");
+		Ok(())
+	}
 }
@@ -0,0 +1,77 @@
+// Copyright © Amanda Graven 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use crate::MappingFile;
+
+impl MappingFile {
+	/// Formats the file as an indented listing for people to review, with each user followed by
+	/// their keys and each key's fields on separate lines. The output is meant for reading only
+	/// and can't be parsed back.
+	///
+	/// ```
+	/// # use pam_u2f_mapping::MappingFile;
+	/// let file: MappingFile = "alice:aGFuZGxl,cHVibGlj,es256,+presence+pin\nbob".parse().unwrap();
+	/// assert_eq!(
+	///     file.to_pretty(),
+	///     "alice\n\
+	///     \x20 key 1\n\
+	///     \x20   handle:    aGFuZGxl\n\
+	///     \x20   public:    cHVibGlj\n\
+	///     \x20   algorithm: ES256 (ECDSA P-256)\n\
+	///     \x20   flags:     presence, pin\n\
+	///     bob\n\
+	///     \x20 no keys\n"
+	/// );
+	/// ```
+	pub fn to_pretty(&self) -> String {
+		let mut out = String::new();
+		for mapping in &self.mappings {
+			// Writing to a String can't fail
+			let _ = writeln!(out, "{}", mapping.user);
+			if mapping.keys.is_empty() {
+				out.push_str("  no keys\n");
+			}
+			for (index, key) in mapping.keys.iter().enumerate() {
+				let mut names = key.flags.names().collect::<Vec<_>>().join(", ");
+				if names.is_empty() {
+					names.push_str("none");
+				}
+				let _ = write!(
+					out,
+					"  key {}\n    handle:    {}\n    public:    {}\n    algorithm: {}\n    \
+					flags:     {names}\n",
+					index + 1,
+					key.handle,
+					key.public,
+					key.kind.label(),
+				);
+			}
+		}
+		out
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::MappingFile;
+
+	#[test]
+	fn pretty() -> Result<(), Box<dyn std::error::Error>> {
+		let file: MappingFile = "alice:a,p,eddsa,:b,q,fancy,+verification+resident".parse()?;
+		assert_eq!(
+			file.to_pretty(),
+			"alice\n  key 1\n    handle:    a\n    public:    p\n    algorithm: EdDSA (Ed25519)\n    \
+			flags:     none\n  key 2\n    handle:    b\n    public:    q\n    algorithm: fancy\n    \
+			flags:     verification, resident\n"
+		);
+		assert_eq!(MappingFile::default().to_pretty(), "");
+		Ok(())
+	}
+}
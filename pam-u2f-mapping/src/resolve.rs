@@ -0,0 +1,103 @@
+// Copyright © Amanda Graven 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use alloc::vec::Vec;
+
+use crate::{Algorithm, Key, Mapping, MappingFile};
+
+/// How the PAM module would handle a user, as worked out by [`MappingFile::resolve`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Resolution<'a> {
+	/// How many mappings the file has for the user. Only the first is used, so any more are
+	/// ignored.
+	pub matches: usize,
+	/// The mapping that's used, i.e. the first one for the user
+	pub mapping: Option<&'a Mapping>,
+	/// The keys of the mapping that can be used to log in, in the order they're tried
+	pub usable_keys: Vec<&'a Key>,
+}
+
+impl MappingFile {
+	/// Works out how the PAM module matches the given user against the file: the first mapping
+	/// for the user is used, and each of its keys is tried in order.
+	///
+	/// A key is considered usable if its algorithm is known and its key handle and public key
+	/// are valid base64. The authenticator itself isn't checked, so a usable key may still fail
+	/// if it was reset or lost.
+	pub fn resolve(&self, user: &str) -> Resolution<'_> {
+		let mut matches = self.mappings.iter().filter(|mapping| mapping.user == user);
+		let mapping = matches.next();
+		let usable_keys = mapping
+			.map(|mapping| mapping.keys.iter().filter(|key| is_usable(key)).collect())
+			.unwrap_or_default();
+		Resolution {
+			matches: mapping.map_or(0, |_| 1 + matches.count()),
+			mapping,
+			usable_keys,
+		}
+	}
+}
+
+/// Whether the PAM module could use a key at all
+fn is_usable(key: &Key) -> bool {
+	!matches!(key.kind, Algorithm::Other(_))
+		&& key.handle_bytes().is_ok()
+		&& key.public_bytes().is_ok()
+}
+
+impl<'a> Resolution<'a> {
+	/// Whether exactly one mapping matches the user
+	pub fn is_unique(&self) -> bool {
+		self.matches == 1
+	}
+
+	/// Whether the user has a key they can log in with
+	pub fn can_log_in(&self) -> bool {
+		!self.usable_keys.is_empty()
+	}
+
+	/// The usable keys that require the PIN
+	pub fn pin_keys(&self) -> impl Iterator<Item = &'a Key> + '_ {
+		self.usable_keys.iter().copied().filter(|key| key.flags.pin)
+	}
+
+	/// The usable keys that require user presence
+	pub fn presence_keys(&self) -> impl Iterator<Item = &'a Key> + '_ {
+		self.usable_keys
+			.iter()
+			.copied()
+			.filter(|key| key.flags.presence)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{Key, MappingFile};
+
+	fn handles<'a>(keys: impl Iterator<Item = &'a Key>) -> Vec<&'a str> {
+		keys.map(|key| key.handle.as_str()).collect()
+	}
+
+	#[test]
+	fn resolve() -> Result<(), Box<dyn std::error::Error>> {
+		let file: MappingFile = "alice:YQ==,cA==,es256,+presence:Yg==,cA==,es256,+pin:c,p,es256,\n\
+			bob:YQ==,cA==,fancy,+presence\n\
+			alice:ZA==,cA==,eddsa,\n"
+			.parse()?;
+		let alice = file.resolve("alice");
+		assert_eq!(alice.matches, 2);
+		assert!(!alice.is_unique() && alice.can_log_in());
+		assert_eq!(handles(alice.usable_keys.iter().copied()), ["YQ==", "Yg=="]);
+		assert_eq!(handles(alice.pin_keys()), ["Yg=="]);
+		assert_eq!(handles(alice.presence_keys()), ["YQ=="]);
+		let bob = file.resolve("bob");
+		assert!(bob.is_unique() && !bob.can_log_in());
+		let carol = file.resolve("carol");
+		assert_eq!((carol.matches, carol.mapping), (0, None));
+		assert!(!carol.can_log_in());
+		Ok(())
+	}
+}
@@ -0,0 +1,133 @@
+// Copyright © Amanda Graven 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use core::ops::{Deref, DerefMut};
+
+use crate::MappingFile;
+
+impl MappingFile {
+	/// Copies the file so it can be restored after a batch of edits. This is the same as
+	/// [`Clone::clone`], the name just states the intent.
+	///
+	/// ```
+	/// use pam_u2f_mapping::MappingFile;
+	///
+	/// let mut file: MappingFile = "alice:h,p,es256,\n".parse().unwrap();
+	/// let snapshot = file.snapshot();
+	/// file.remove_user("alice");
+	/// if !file.rename_user("bob", "carol") {
+	///     file = snapshot;
+	/// }
+	/// assert!(file.contains_user("alice"));
+	/// ```
+	pub fn snapshot(&self) -> MappingFile {
+		self.clone()
+	}
+
+	/// Starts a batch of edits that are undone unless [`Transaction::commit`] is called. The
+	/// transaction dereferences to the file, so it's edited as usual. If it's dropped without
+	/// being committed, e.g. because an edit failed and `?` returned early, the file is restored
+	/// to how it was when the transaction started.
+	///
+	/// ```
+	/// use pam_u2f_mapping::MappingFile;
+	///
+	/// fn swap(file: &mut MappingFile, a: &str, b: &str) -> Result<(), &'static str> {
+	///     let mut tx = file.transaction();
+	///     tx.rename_user(a, "\0").then_some(()).ok_or("no such user")?;
+	///     tx.rename_user(b, a).then_some(()).ok_or("no such user")?;
+	///     tx.rename_user("\0", b);
+	///     tx.commit();
+	///     Ok(())
+	/// }
+	///
+	/// let mut file: MappingFile = "alice:h,p,es256,\n".parse().unwrap();
+	/// assert!(swap(&mut file, "alice", "bob").is_err());
+	/// assert!(file.contains_user("alice"));
+	/// ```
+	pub fn transaction(&mut self) -> Transaction<'_> {
+		Transaction {
+			original: Some(self.snapshot()),
+			file: self,
+		}
+	}
+}
+
+/// A batch of edits to a [`MappingFile`], created with [`MappingFile::transaction`]. Rolls the
+/// file back when dropped, unless committed.
+#[derive(Debug)]
+#[must_use = "dropping a transaction immediately rolls it back"]
+pub struct Transaction<'a> {
+	file: &'a mut MappingFile,
+	/// The file as it was when the transaction started, taken when committing
+	original: Option<MappingFile>,
+}
+
+impl<'a> Transaction<'a> {
+	/// Keeps the edits made during the transaction
+	pub fn commit(mut self) {
+		self.original = None;
+	}
+
+	/// Undoes the edits made during the transaction. This is the same as dropping it.
+	pub fn rollback(self) {}
+
+	/// The file as it was when the transaction started
+	pub fn original(&self) -> &MappingFile {
+		// Only taken in commit, which consumes the transaction
+		self.original.as_ref().unwrap_or(self.file)
+	}
+}
+
+impl Deref for Transaction<'_> {
+	type Target = MappingFile;
+
+	fn deref(&self) -> &MappingFile {
+		self.file
+	}
+}
+
+impl DerefMut for Transaction<'_> {
+	fn deref_mut(&mut self) -> &mut MappingFile {
+		self.file
+	}
+}
+
+impl Drop for Transaction<'_> {
+	fn drop(&mut self) {
+		if let Some(original) = self.original.take() {
+			*self.file = original;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::MappingFile;
+
+	#[test]
+	fn transaction() -> Result<(), Box<dyn std::error::Error>> {
+		let mut file: MappingFile = "alice:h,p,es256,\nbob\n".parse()?;
+		let before = file.snapshot();
+		{
+			let mut tx = file.transaction();
+			tx.remove_user("alice");
+			tx.add_user("carol")?;
+			assert_eq!(tx.original(), &before);
+			assert_eq!(tx.len(), 2);
+		}
+		assert_eq!(file, before);
+		let mut tx = file.transaction();
+		tx.remove_user("bob");
+		tx.rollback();
+		assert_eq!(file, before);
+		let mut tx = file.transaction();
+		tx.remove_user("bob");
+		tx.commit();
+		assert_eq!(file.to_string(), "alice:h,p,es256,\n");
+		Ok(())
+	}
+}
@@ -0,0 +1,266 @@
+// Copyright © Amanda Graven 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::{entry_lines, Mapping, MappingFile};
+
+/// Something in a mapping file that doesn't prevent it from being parsed, but is likely a
+/// mistake
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationWarning {
+	/// The line the problem is on, starting at 1
+	pub line: usize,
+	/// What the problem is
+	pub kind: WarningKind,
+}
+
+/// The kinds of problems reported by [`MappingFile::validate`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WarningKind {
+	/// The user was already mapped on an earlier line. PAM only uses the first mapping for a
+	/// user, so the keys on this line are ignored.
+	DuplicateUser {
+		/// The duplicated username
+		user: String,
+		/// The line of the first mapping for the user
+		first_line: usize,
+	},
+	/// The username can't belong to a regular account, so PAM will never match it
+	InvalidUsername {
+		/// The username as written in the file
+		user: String,
+		/// Why the username is suspicious
+		problem: UsernameProblem,
+	},
+	/// A key has a flag that isn't `presence`, `pin` or `verification`. It may be a typo, or a
+	/// flag added by a newer version of `pamu2fcfg`. Unknown flags are kept when formatting.
+	UnknownFlag {
+		/// The user the key is mapped to
+		user: String,
+		/// The flag as written in the file
+		flag: String,
+	},
+	/// The line ends in whitespace. It's ignored when parsing, but other tools reading the file
+	/// may take it to be part of the last flag.
+	TrailingWhitespace,
+}
+
+/// Reasons a username is unlikely to match an account
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsernameProblem {
+	/// The username is empty
+	Empty,
+	/// The username contains whitespace, e.g. a trailing space left by an editor
+	Whitespace,
+	/// The username contains a control character
+	ControlCharacter,
+	/// The username starts with `-`, which POSIX doesn't allow
+	LeadingHyphen,
+	/// The username is longer than [`UsernameProblem::MAX_LEN`] bytes
+	TooLong,
+}
+
+impl UsernameProblem {
+	/// The longest username `useradd(8)` accepts by default
+	pub const MAX_LEN: usize = 32;
+
+	/// Checks a username against the rules account management tools apply. Policies vary
+	/// between systems, so a username passing these checks may still be rejected locally.
+	pub fn check(user: &str) -> Option<UsernameProblem> {
+		if user.is_empty() {
+			Some(UsernameProblem::Empty)
+		} else if user.chars().any(char::is_whitespace) {
+			Some(UsernameProblem::Whitespace)
+		} else if user.chars().any(char::is_control) {
+			Some(UsernameProblem::ControlCharacter)
+		} else if user.starts_with('-') {
+			Some(UsernameProblem::LeadingHyphen)
+		} else if user.len() > UsernameProblem::MAX_LEN {
+			Some(UsernameProblem::TooLong)
+		} else {
+			None
+		}
+	}
+}
+
+impl MappingFile {
+	/// Checks a mapping file for likely mistakes. Lines that fail to parse are skipped, as those
+	/// are reported when parsing the file.
+	pub fn validate(s: &str) -> Vec<ValidationWarning> {
+		let mut warnings = Vec::new();
+		let mut users = BTreeMap::new();
+		for (line, text) in entry_lines(s) {
+			let Ok(mapping) = text.parse::<Mapping>() else {
+				continue;
+			};
+			if text.trim_end().len() != text.len() {
+				warnings.push(ValidationWarning {
+					line,
+					kind: WarningKind::TrailingWhitespace,
+				});
+			}
+			if let Some(problem) = UsernameProblem::check(&mapping.user) {
+				warnings.push(ValidationWarning {
+					line,
+					kind: WarningKind::InvalidUsername {
+						user: mapping.user.clone(),
+						problem,
+					},
+				});
+			}
+			for key in &mapping.keys {
+				for flag in &key.flags.other {
+					warnings.push(ValidationWarning {
+						line,
+						kind: WarningKind::UnknownFlag {
+							user: mapping.user.clone(),
+							flag: flag.clone(),
+						},
+					});
+				}
+			}
+			if let Some(&first_line) = users.get(&mapping.user) {
+				warnings.push(ValidationWarning {
+					line,
+					kind: WarningKind::DuplicateUser {
+						user: mapping.user,
+						first_line,
+					},
+				});
+			} else {
+				users.insert(mapping.user, line);
+			}
+		}
+		warnings
+	}
+}
+
+impl core::fmt::Display for ValidationWarning {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		write!(f, "line {}: ", self.line)?;
+		match &self.kind {
+			WarningKind::DuplicateUser { user, first_line } => write!(
+				f,
+				"User {user} is already mapped on line {first_line}, this entry is ignored"
+			),
+			WarningKind::InvalidUsername { user, problem } => {
+				write!(f, "Username {user:?} {problem}")
+			}
+			WarningKind::UnknownFlag { user, flag } => {
+				write!(f, "Key for {user} has unknown flag {flag:?}")
+			}
+			WarningKind::TrailingWhitespace => f.write_str("Line ends in whitespace"),
+		}
+	}
+}
+
+impl core::fmt::Display for UsernameProblem {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		use UsernameProblem::*;
+		let s = match *self {
+			Empty => "is empty",
+			Whitespace => "contains whitespace",
+			ControlCharacter => "contains a control character",
+			LeadingHyphen => "starts with a hyphen",
+			TooLong => "is too long",
+		};
+		f.write_str(s)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{MappingFile, UsernameProblem, ValidationWarning, WarningKind};
+
+	#[test]
+	fn duplicate_users() {
+		let file = "alice:h,p,es256,\nbob\n\nalice:x\nalice\n";
+		let warnings = MappingFile::validate(file);
+		assert_eq!(
+			warnings,
+			[ValidationWarning {
+				line: 5,
+				kind: WarningKind::DuplicateUser {
+					user: "alice".into(),
+					first_line: 1,
+				},
+			}]
+		);
+		assert_eq!(
+			warnings[0].to_string(),
+			"line 5: User alice is already mapped on line 1, this entry is ignored"
+		);
+	}
+
+	#[test]
+	fn unknown_flags() {
+		let file = "alice:h,p,es256,+presence+pni\nbob:h,p,es256,+resident:k,p,es256,+pin\n";
+		let flags = MappingFile::validate(file)
+			.into_iter()
+			.map(|warning| match warning.kind {
+				WarningKind::UnknownFlag { user, flag } => (warning.line, user, flag),
+				other => panic!("unexpected warning {other:?}"),
+			})
+			.collect::<Vec<_>>();
+		assert_eq!(
+			flags,
+			[
+				(1, "alice".into(), "pni".into()),
+				(2, "bob".into(), "resident".into()),
+			]
+		);
+		assert_eq!(
+			MappingFile::validate(file)[0].to_string(),
+			"line 1: Key for alice has unknown flag \"pni\""
+		);
+		assert_eq!(file.parse::<MappingFile>().unwrap().to_string(), file);
+	}
+
+	#[test]
+	fn usernames() {
+		let file = "al ice\n-bob\nok\nmallory\u{7}\n".to_owned() + &"x".repeat(33);
+		let problems = MappingFile::validate(&file)
+			.into_iter()
+			.map(|warning| match warning.kind {
+				WarningKind::InvalidUsername { problem, .. } => (warning.line, problem),
+				other => panic!("unexpected warning {other:?}"),
+			})
+			.collect::<Vec<_>>();
+		assert_eq!(
+			problems,
+			[
+				(1, UsernameProblem::Whitespace),
+				(2, UsernameProblem::LeadingHyphen),
+				(4, UsernameProblem::ControlCharacter),
+				(5, UsernameProblem::TooLong),
+			]
+		);
+		assert_eq!(UsernameProblem::check(""), Some(UsernameProblem::Empty));
+		assert_eq!(
+			MappingFile::validate("al ice\n")[0].to_string(),
+			"line 1: Username \"al ice\" contains whitespace"
+		);
+	}
+
+	#[test]
+	fn trailing_whitespace() {
+		let file = "alice:h,p,es256,+presence \nbob:h,p,es256,\r\n";
+		assert_eq!(
+			MappingFile::validate(file),
+			[ValidationWarning {
+				line: 1,
+				kind: WarningKind::TrailingWhitespace,
+			}]
+		);
+		assert_eq!(
+			MappingFile::validate(file)[0].to_string(),
+			"line 1: Line ends in whitespace"
+		);
+	}
+}